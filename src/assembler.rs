@@ -0,0 +1,212 @@
+use std::path::PathBuf;
+use crate::diagnostic::{Diagnostic, Severity};
+use crate::encoder::{self, Byte, Segment};
+use crate::lexer::TokenStream;
+use crate::output;
+use crate::parser::{self, Context, Instruction};
+use crate::source::{Span, Sources};
+use crate::symbols::Symbols;
+
+// Everything needed to assemble a program in-process. Sources are assembled in the order they're added,
+// as if each one was included at the end of the one before.
+pub struct Assembler {
+	sources: Sources,
+	files: Vec<usize>,
+	defines: Vec<(String, u16)>,
+}
+
+// One instruction, where it ended up and what it assembled to.
+#[derive(Clone)]
+#[derive(Debug)]
+pub struct Listing {
+	pub address: u16,
+	pub words: Vec<u16>,
+	pub span: Span,
+}
+
+#[derive(Debug)]
+pub struct Program {
+	pub segments: Vec<Segment<u16>>,
+	pub symbols: Symbols,
+	pub listing: Vec<Listing>,
+	pub warnings: Vec<Diagnostic>,
+}
+
+impl Program {
+	// The whole image from the lowest origin up, with the gaps between segments zeroed.
+	pub fn words(&self) -> (u16, Vec<u16>) {
+		output::flatten(&self.segments)
+	}
+}
+
+impl Default for Assembler {
+	fn default() -> Assembler {
+		Assembler::new()
+	}
+}
+
+impl Assembler {
+	pub fn new() -> Assembler {
+		Assembler {sources: Sources::new(vec!()), files: vec!(), defines: vec!()}
+	}
+	
+	// Searched for INCLUDE and INCBIN files that aren't next to the file that wants them.
+	pub fn include_dir(&mut self, dir: PathBuf) -> &mut Assembler {
+		self.sources.add_include_dir(dir);
+		self
+	}
+	
+	// `path` is what INCLUDEs in the source are relative to, if it has one.
+	pub fn add_source(&mut self, name: String, path: Option<PathBuf>, text: String) -> &mut Assembler {
+		let file = self.sources.add(name, path, text);
+		self.files.push(file);
+		self
+	}
+	
+	// Like -D, a constant that's defined before any of the source.
+	pub fn define(&mut self, name: &str, value: u16) -> &mut Assembler {
+		self.defines.push((name.to_owned(), value));
+		self
+	}
+	
+	// Everything the diagnostics point into, for rendering them.
+	pub fn sources(&self) -> &Sources {
+		&self.sources
+	}
+	
+	// Errors come back along with any warnings, in the order they were found.
+	pub fn assemble(&mut self) -> Result<Program, Vec<Diagnostic>> {
+		// Included files and expansions are gathered again every time, or INCLUDE would skip the files the
+		// last call brought in as already included.
+		let mut sources = Sources::new(self.sources.include_dirs().to_vec());
+		self.files = self.files.iter().map(|file| {
+			let file = self.sources.get(*file);
+			sources.add(file.name.clone(), file.path.clone(), file.text.clone())
+		}).collect();
+		
+		let mut context = Context::new(sources);
+		let result = assemble_files(&self.files, &self.defines, &mut context);
+		self.sources = context.sources;
+		result
+	}
+}
+
+fn assemble_files(files: &[usize], defines: &[(String, u16)], context: &mut Context) -> Result<Program, Vec<Diagnostic>> {
+	let mut segments: Vec<Segment<Byte>> = vec!(Segment::new(0, None));
+	let mut symbols = Symbols::new();
+	let mut diagnostics: Vec<Diagnostic> = vec!();
+	let mut placed: Vec<(u16, usize, Span)> = vec!();
+	
+	for (name, value) in defines.iter() {
+		symbols.define_external(name, *value);
+	}
+	
+	for file in files.iter() {
+		let mut lex = TokenStream::new(context.sources.tokens(*file), *file);
+		
+		loop {
+			let location = encoder::location(&segments);
+			match parser::parse(&mut lex, context, &symbols.values, location) {
+				Ok(Some((instruction, span))) => {
+					let (segment_count, origin, length) = match segments.last() {
+						Some(segment) => (segments.len(), segment.origin, segment.words.len()),
+						None => (0, 0, 0),
+					};
+					match encoder::partially_encode(&instruction, &span, &mut symbols, &mut segments) {
+						Ok(warnings) => diagnostics.extend(warnings),
+						Err(diagnostic) => {
+							diagnostics.push(diagnostic);
+							continue;
+						},
+					}
+					// ORG and RESERVE start a new segment, so anything in the last one now was put there by this instruction.
+					let count = match segments.last() {
+						Some(segment) if segments.len() == segment_count && segment.origin == origin => segment.words.len() - length,
+						Some(segment) => segment.words.len(),
+						None => 0,
+					};
+					// Everything else goes where it was parsed, but ORG is listed at the address it moves to.
+					let address = match instruction {
+						Instruction::ORG(..) => encoder::location(&segments).address,
+						_ => location.address,
+					};
+					placed.push((address, count, span));
+				},
+				Ok(None) => break,
+				Err(diagnostic) => diagnostics.push(diagnostic),
+			};
+		}
+	}
+	
+	let segments = match encoder::encode_identifiers(&symbols, &segments) {
+		Ok(segments) => segments,
+		Err(errors) => {
+			diagnostics.extend(errors);
+			vec!()
+		},
+	};
+	
+	if diagnostics.iter().any(|diagnostic| diagnostic.severity == Severity::Error) {
+		return Err(diagnostics);
+	}
+	
+	let listing = placed.into_iter().map(|(address, count, span)| Listing {address, words: words_at(&segments, address, count), span}).collect();
+	
+	Ok(Program {segments, symbols, listing, warnings: diagnostics})
+}
+
+fn words_at(segments: &[Segment<u16>], address: u16, count: usize) -> Vec<u16> {
+	let start = address as u32;
+	match segments.iter().find(|segment| segment.origin <= start && start + count as u32 <= segment.end()) {
+		Some(segment) => segment.words[(start - segment.origin) as usize..][..count].to_vec(),
+		None => vec!(),
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use std::fs;
+	use super::*;
+	
+	fn words(assembler: &mut Assembler) -> Vec<u16> {
+		assembler.assemble().map_err(|diagnostics| format!("{:?}", diagnostics)).unwrap().words().1
+	}
+	
+	#[test]
+	fn assemble_twice() {
+		let dir = std::env::temp_dir().join(format!("asm-19_assembler_test_{}", std::process::id()));
+		fs::create_dir_all(&dir).unwrap();
+		fs::write(dir.join("answer.a19"), "DATA 42").unwrap();
+		
+		let mut assembler = Assembler::new();
+		assembler.include_dir(dir.clone());
+		assembler.add_source("main.a19".to_owned(), None, "INCLUDE \"answer.a19\"\nDATA 1".to_owned());
+		let first = words(&mut assembler);
+		let second = words(&mut assembler);
+		fs::remove_dir_all(&dir).unwrap();
+		
+		assert_eq!(first, vec!(42, 1));
+		assert_eq!(second, first);
+	}
+	
+	#[test]
+	fn sources_and_defines() {
+		let mut assembler = Assembler::new();
+		assembler.define("SIZE", 3)
+			.add_source("first.a19".to_owned(), None, "MARK Start\nDATA SIZE".to_owned())
+			.add_source("second.a19".to_owned(), None, "DATA Start Later\nMARK Later".to_owned());
+		
+		let program = assembler.assemble().unwrap();
+		assert_eq!(program.words(), (0, vec!(3, 0, 3)));
+		assert_eq!(program.symbols.values["Later"], 3);
+		assert_eq!(program.listing.iter().map(|entry| entry.address).collect::<Vec<u16>>(), vec!(0, 0, 1, 3));
+		assert!(assembler.assemble().is_ok());
+	}
+	
+	#[test]
+	fn errors() {
+		let mut assembler = Assembler::new();
+		assembler.add_source("main.a19".to_owned(), None, "DATA Missing\nSET A 0x10000".to_owned());
+		assert_eq!(assembler.assemble().unwrap_err().len(), 2);
+	}
+}
\ No newline at end of file
@@ -0,0 +1,201 @@
+use std::path::PathBuf;
+use logos::Logos;
+use termcolor::ColorChoice;
+use asm_19_assembler::lexer::Token;
+use asm_19_assembler::output::{Format, Memory};
+
+pub const USAGE: &str = "\
+Usage: asm-19_assembler [OPTIONS] <INPUT>
+
+Assembles an A19 source file. Pass - as the input to read the source from stdin.
+
+Options:
+  -o, --output <PATH>        Write the assembled program to PATH, or into PATH if it is a directory
+                             (- for stdout) [default: INPUT with the format's extension]
+      --listing <PATH>       Also write a listing of every source line with its address and encoded words
+  -I <DIR>                   Also search DIR for INCLUDE and INCBIN files, can be repeated
+  -D <NAME>[=VALUE]          Define a constant for IF and IFDEF, and the rest of the source [default VALUE: 1]
+  -f, --format <FORMAT>      Output format [default: bin]
+                               bin     raw big-endian words
+                               bin-le  raw little-endian words
+                               ihex    Intel HEX with an address for every byte
+                               ihex16  Intel HEX with an address for every word
+                               srec    Motorola S-records with an address for every byte
+                               logisim Logisim v2.0 raw memory image
+                               memh    one hex word per line, for Verilog's $readmemh
+                               memb    one binary word per line, for Verilog's $readmemb
+                               mif     Altera Memory Initialization File
+                               coe     Xilinx coefficient file
+      --depth <WORDS>        Size of the memory for logisim, memh, memb, mif and coe images
+                             [default: up to the end of the program]
+      --fill <VALUE>         Word to put in the parts of the memory image the program doesn't use [default: 0]
+  -q, --quiet                Only print diagnostics
+  -v, --verbose              Also print the token stream and the listing
+      --hold                 Pause the verbose token stream every 30 tokens
+      --color <WHEN>         Colorize diagnostics: auto, always or never [default: auto]
+  -h, --help                 Print this message
+";
+
+#[derive(Clone, Copy)]
+#[derive(Debug)]
+#[derive(PartialEq, PartialOrd)]
+pub enum Verbosity {
+	Quiet,
+	Normal,
+	Verbose,
+}
+
+#[derive(Debug)]
+pub struct Options {
+	pub input: PathBuf,
+	pub output: Option<PathBuf>,
+	pub listing: Option<PathBuf>,
+	pub include_dirs: Vec<PathBuf>,
+	pub defines: Vec<(String, u16)>,
+	pub format: Format,
+	pub memory: Memory,
+	pub verbosity: Verbosity,
+	pub color: ColorChoice,
+	pub hold: bool,
+}
+
+#[derive(Debug)]
+pub enum Command {
+	Assemble(Options),
+	Help,
+}
+
+impl Options {
+	pub fn reads_stdin(&self) -> bool {
+		self.input.as_os_str() == "-"
+	}
+	
+	pub fn input_name(&self) -> String {
+		if self.reads_stdin() {"<stdin>".to_owned()} else {self.input.to_string_lossy().into_owned()}
+	}
+}
+
+pub fn parse_args<I: Iterator<Item = String>>(mut args: I) -> Result<Command, String> {
+	let mut input: Option<PathBuf> = None;
+	let mut output: Option<PathBuf> = None;
+	let mut listing: Option<PathBuf> = None;
+	let mut include_dirs: Vec<PathBuf> = vec!();
+	let mut defines: Vec<(String, u16)> = vec!();
+	let mut format = Format::Binary;
+	let mut memory = Memory {depth: None, fill: 0};
+	let mut verbosity = Verbosity::Normal;
+	let mut color = ColorChoice::Auto;
+	let mut hold = false;
+	
+	while let Some(arg) = args.next() {
+		// Long options may carry their value after an equals sign instead of in the next argument.
+		let (flag, inline_value) = match arg.find('=') {
+			Some(index) if arg.starts_with("--") => (arg[..index].to_owned(), Some(arg[index + 1..].to_owned())),
+			_ => (arg.clone(), None),
+		};
+		let mut value = |name: &str| match inline_value.clone().or_else(|| args.next()) {
+			Some(value) => Ok(value),
+			None => Err(format!("{} expects a value", name)),
+		};
+		
+		match flag.as_str() {
+			"-h" | "--help" => return Ok(Command::Help),
+			"-o" | "--output" => output = Some(PathBuf::from(value(&flag)?)),
+			"--listing" => listing = Some(PathBuf::from(value(&flag)?)),
+			"-I" => include_dirs.push(PathBuf::from(value(&flag)?)),
+			_ if flag.starts_with("-I") => include_dirs.push(PathBuf::from(&flag[2..])),
+			"-D" => defines.push(parse_define(&value(&flag)?)?),
+			_ if flag.starts_with("-D") => defines.push(parse_define(&flag[2..])?),
+			"-f" | "--format" => {
+				let name = value(&flag)?;
+				format = match Format::from_name(&name) {
+					Some(format) => format,
+					None => return Err(format!("Unknown output format \"{}\"", name)),
+				};
+			},
+			"--depth" => {
+				let depth = value(&flag)?;
+				memory.depth = match parse_number(&depth) {
+					Some(depth) if depth <= 0x1_0000 => Some(depth),
+					_ => return Err(format!("--depth expects a number of words up to 65536, got \"{}\"", depth)),
+				};
+			},
+			"--fill" => {
+				let fill = value(&flag)?;
+				memory.fill = match parse_number(&fill) {
+					Some(fill) if fill <= 0xFFFF => fill as u16,
+					_ => return Err(format!("--fill expects a word, got \"{}\"", fill)),
+				};
+			},
+			"-q" | "--quiet" => verbosity = Verbosity::Quiet,
+			"-v" | "--verbose" => verbosity = Verbosity::Verbose,
+			"--hold" => hold = true,
+			"--color" | "--colour" => {
+				color = match value(&flag)?.as_str() {
+					"auto" => ColorChoice::Auto,
+					"always" => ColorChoice::Always,
+					"never" => ColorChoice::Never,
+					other => return Err(format!("Unknown color mode \"{}\", expected auto, always or never", other)),
+				};
+			},
+			_ if flag.starts_with('-') && flag != "-" => return Err(format!("Unknown option \"{}\"", flag)),
+			_ => match input {
+				Some(_) => return Err(format!("Unexpected argument \"{}\", only one input file is supported", arg)),
+				None => input = Some(PathBuf::from(arg)),
+			},
+		}
+	}
+	
+	let input = match input {
+		Some(input) => input,
+		None => return Err("No input file given".to_owned()),
+	};
+	
+	Ok(Command::Assemble(Options {
+		input,
+		output,
+		listing,
+		include_dirs,
+		defines,
+		format,
+		memory,
+		verbosity,
+		color,
+		hold,
+	}))
+}
+
+// Takes the name and value the same way the source would, so `-D DEBUG` and `-D LEVEL=0x10` both work.
+fn parse_define(definition: &str) -> Result<(String, u16), String> {
+	let (name, value) = match definition.find('=') {
+		Some(index) => (&definition[..index], &definition[index + 1..]),
+		None => (definition, "1"),
+	};
+	
+	let mut lex = Token::lexer(name);
+	if !matches!((lex.next(), lex.next()), (Some(Token::Identifier), None)) {
+		return Err(format!("-D expects a name, got \"{}\"", name));
+	}
+	
+	// Negative values are stored as the word they'd encode to, like any other symbol.
+	let (negative, digits) = match value.strip_prefix('-') {
+		Some(digits) => (true, digits),
+		None => (false, value),
+	};
+	let mut lex = Token::lexer(digits);
+	match (lex.next(), lex.extras.take(), lex.next()) {
+		(Some(Token::Number(number)), _, None) if !negative => Ok((name.to_owned(), number)),
+		(Some(Token::Number(number)), _, None) if number <= 0x8000 => Ok((name.to_owned(), number.wrapping_neg())),
+		(Some(Token::Number(_)), _, None) | (_, Some(_), None) => Err(format!("-D {} value \"{}\" doesn't fit in a word", name, value)),
+		_ => Err(format!("-D {} expects a number, got \"{}\"", name, value)),
+	}
+}
+
+// Sizes can be bigger than a word, so these don't go through the lexer like -D values do.
+fn parse_number(text: &str) -> Option<u32> {
+	let digits = text.replace('_', "");
+	match digits.strip_prefix("0x").or_else(|| digits.strip_prefix("0X")) {
+		Some(hex) => u32::from_str_radix(hex, 16).ok(),
+		None => digits.parse().ok(),
+	}
+}
\ No newline at end of file
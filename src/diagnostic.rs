@@ -0,0 +1,153 @@
+use std::io;
+use termcolor::{Color, ColorSpec, WriteColor};
+use crate::source::{Span, Sources};
+
+#[derive(Clone, Copy)]
+#[derive(Debug)]
+#[derive(PartialEq)]
+pub enum Severity {
+	Error,
+	Warning,
+}
+
+#[derive(Clone)]
+#[derive(Debug)]
+pub struct Diagnostic {
+	pub severity: Severity,
+	pub span: Span,
+	pub message: String,
+	pub hints: Vec<String>,
+	pub notes: Vec<(Span, String)>,
+}
+
+impl Diagnostic {
+	pub fn error<S: Into<String>>(span: Span, message: S) -> Diagnostic {
+		Diagnostic {
+			severity: Severity::Error,
+			span,
+			message: message.into(),
+			hints: vec!(),
+			notes: vec!(),
+		}
+	}
+	
+	pub fn warning<S: Into<String>>(span: Span, message: S) -> Diagnostic {
+		Diagnostic {severity: Severity::Warning, ..Diagnostic::error(span, message)}
+	}
+	
+	pub fn with_hint<S: Into<String>>(mut self, hint: S) -> Diagnostic {
+		self.hints.push(hint.into());
+		self
+	}
+	
+	// Points at a second place in the source that's relevant, like an earlier definition.
+	pub fn with_note<S: Into<String>>(mut self, span: Span, note: S) -> Diagnostic {
+		self.notes.push((span, note.into()));
+		self
+	}
+	
+	pub fn render<W: WriteColor>(&self, out: &mut W, sources: &Sources) -> io::Result<()> {
+		let (label, color) = match self.severity {
+			Severity::Error		=> ("error", Color::Red),
+			Severity::Warning	=> ("warning", Color::Yellow),
+		};
+		
+		out.set_color(ColorSpec::new().set_fg(Some(color)).set_bold(true))?;
+		write!(out, "{}", label)?;
+		out.set_color(ColorSpec::new().set_bold(true))?;
+		writeln!(out, ": {}", self.message)?;
+		out.reset()?;
+		
+		let gutter = snippet(out, sources, &self.span, color)?;
+		
+		for hint in self.hints.iter() {
+			out.set_color(ColorSpec::new().set_fg(Some(Color::Blue)).set_bold(true))?;
+			write!(out, "{} = ", gutter)?;
+			out.set_color(ColorSpec::new().set_bold(true))?;
+			write!(out, "hint")?;
+			out.reset()?;
+			writeln!(out, ": {}", hint)?;
+		}
+		
+		for (span, note) in self.notes.iter() {
+			out.set_color(ColorSpec::new().set_bold(true))?;
+			writeln!(out, "note: {}", note)?;
+			out.reset()?;
+			snippet(out, sources, span, Color::Blue)?;
+		}
+		
+		// Anything that went wrong inside a macro or REPT also shows where that was used, all the way out.
+		// Recursive macros repeat the same invocation, which only needs showing once.
+		let mut expansion = self.span.expansion;
+		while let Some(id) = expansion {
+			let record = sources.expansion(id);
+			let mut repeats = 1;
+			expansion = record.invocation.expansion;
+			while let Some(next) = expansion.map(|next| sources.expansion(next)) {
+				let (here, there) = (&record.invocation, &next.invocation);
+				if (here.file, here.start, here.end) != (there.file, there.start, there.end) {
+					break;
+				}
+				repeats += 1;
+				expansion = next.invocation.expansion;
+			}
+			
+			out.set_color(ColorSpec::new().set_bold(true))?;
+			if repeats == 1 {
+				writeln!(out, "note: in {}", record.description)?;
+			} else {
+				writeln!(out, "note: in {}, nested {} times", record.description, repeats)?;
+			}
+			out.reset()?;
+			snippet(out, sources, &record.invocation, Color::Blue)?;
+		}
+		
+		out.reset()?;
+		writeln!(out)
+	}
+}
+
+// Prints the location and line a span points at with the span underlined, and returns the gutter used.
+fn snippet<W: WriteColor>(out: &mut W, sources: &Sources, span: &Span, color: Color) -> io::Result<String> {
+	let file = sources.get(span.file);
+	let (path, source) = (&file.name, &file.text);
+	let (line_number, line_start, line) = locate(source, span.start);
+	let column = source[line_start..span.start.min(line_start + line.len())].chars().count() + 1;
+	let gutter = " ".repeat(line_number.to_string().len());
+	
+	out.set_color(ColorSpec::new().set_fg(Some(Color::Blue)).set_bold(true))?;
+	write!(out, "{}--> ", gutter)?;
+	out.reset()?;
+	writeln!(out, "{}:{}:{}", path, line_number, column)?;
+	
+	out.set_color(ColorSpec::new().set_fg(Some(Color::Blue)).set_bold(true))?;
+	writeln!(out, "{} |", gutter)?;
+	write!(out, "{} | ", line_number)?;
+	out.reset()?;
+	writeln!(out, "{}", line.replace('\t', "    "))?;
+	
+	// Tabs are widened above, so the underline has to be widened to match.
+	let underline_start = span.start.min(line_start + line.len());
+	let underline_end = span.end.clamp(underline_start, line_start + line.len());
+	let padding: usize = source[line_start..underline_start].chars().map(|c| if c == '\t' {4} else {1}).sum();
+	let width = source[underline_start..underline_end].chars().count().max(1);
+	
+	out.set_color(ColorSpec::new().set_fg(Some(Color::Blue)).set_bold(true))?;
+	write!(out, "{} | ", gutter)?;
+	out.set_color(ColorSpec::new().set_fg(Some(color)).set_bold(true))?;
+	writeln!(out, "{}{}", " ".repeat(padding), "^".repeat(width))?;
+	out.reset()?;
+	
+	Ok(gutter)
+}
+
+// Returns the 1-based line number of a byte offset, the offset that line starts at, and its text.
+fn locate(source: &str, offset: usize) -> (usize, usize, &str) {
+	let offset = offset.min(source.len());
+	let line_start = source[..offset].rfind('\n').map_or(0, |index| index + 1);
+	let line_end = source[offset..].find('\n').map_or(source.len(), |index| offset + index);
+	let line = source[line_start..line_end].trim_end_matches('\r');
+	let line_number = source[..line_start].matches('\n').count() + 1;
+	
+	(line_number, line_start, line)
+}
\ No newline at end of file
@@ -1,154 +1,390 @@
-use std::collections::HashMap;
+use std::{
+	fs,
+	path::Path,
+};
 use crate::parser::*;
 use crate::keywords::*;
+use crate::diagnostic::Diagnostic;
+use crate::expression::{Expression, Location};
+use crate::source::Span;
+use crate::symbols::{Symbols, SymbolKind};
 
+#[derive(Clone)]
 #[derive(Debug)]
 pub enum Byte {
 	Definite(u16),
-	Identifier(String),
-	FromMemWithIdentifier(FromMem),
+	Expression(Expression, Location),
+	FromMemWithExpression(FromMem, Location),
 }
 
-pub fn partially_encode(instruction: &Instruction, constants: &mut HashMap<String, u16>, byte_address: u16) -> Vec<Byte> {
-	match instruction {
-		Instruction::CONST(name, value) => {
-			println!("Added constant {}, {}", name, *value);
-			constants.insert(name.to_owned(), *value);
+// A run of words placed at a fixed address by ORG.
+#[derive(Debug)]
+pub struct Segment<T> {
+	pub origin: u32,
+	pub words: Vec<T>,
+	pub span: Option<Span>,
+}
+
+impl<T> Segment<T> {
+	pub fn new(origin: u32, span: Option<Span>) -> Segment<T> {
+		Segment {origin, words: vec!(), span}
+	}
+	
+	pub fn end(&self) -> u32 {
+		self.origin + self.words.len() as u32
+	}
+}
+
+pub fn location(segments: &[Segment<Byte>]) -> Location {
+	match segments.last() {
+		Some(segment) => Location {address: segment.end() as u16, section_start: segment.origin as u16},
+		None => Location {address: 0, section_start: 0},
+	}
+}
+
+// Anything worth a warning doesn't stop the instruction from being encoded, so warnings come back with the success.
+pub fn partially_encode(instruction: &Instruction, span: &Span, symbols: &mut Symbols, segments: &mut Vec<Segment<Byte>>) -> Result<Vec<Diagnostic>, Diagnostic> {
+	let location = location(segments);
+	// Unlike `location`, this can reach 0x10000, so anything placed after the last word of memory is caught
+	// rather than wrapping around to 0.
+	let end = segments.last().map_or(0, |segment| segment.end());
+	let mut warnings = vec!();
+	
+	let bytes = match instruction {
+		Instruction::ORG(address, span) => {
+			let origin = address.evaluate(&symbols.values, location)?;
+			if !(0..=0xFFFF).contains(&origin) {
+				return Err(Diagnostic::error(span.clone(), format!("ORG address must be between 0 and 65535, got {}", origin)));
+			}
+			start_segment(segments, origin as u32, span);
+			vec!()
+		},
+		Instruction::RESERVE(count, span) => {
+			// Reserved words are left out of the image entirely, flattening zeroes them along with any other gap.
+			let count = evaluate_count(count, span, "RESERVE", symbols, location)?;
+			let end = location.address as u32 + count as u32;
+			if end > 0x1_0000 {
+				return Err(Diagnostic::error(span.clone(), format!("RESERVE runs past the end of memory, to {:#07X}", end))
+					.with_hint(format!("It starts at {:#06X} and memory ends at 0xFFFF", location.address)));
+			}
+			start_segment(segments, end, span);
+			vec!()
+		},
+		Instruction::FILL(count, value, span) => {
+			let count = evaluate_count(count, span, "FILL", symbols, location)?;
+			vec!(encode_literal(value, symbols, location)?; count as usize)
+		},
+		Instruction::ALIGN(alignment, fill, span) => {
+			let alignment = evaluate_count(alignment, span, "ALIGN", symbols, location)?;
+			if alignment == 0 {
+				return Err(Diagnostic::error(span.clone(), "ALIGN needs an alignment of at least 1"));
+			}
+			let padding = (alignment - location.address % alignment) % alignment;
+			let fill = match fill {
+				Some(fill) => encode_literal(fill, symbols, location)?,
+				None => Byte::Definite(0x0000),
+			};
+			vec!(fill; padding as usize)
+		},
+		Instruction::INCBIN(path, offset, length, span) => {
+			let data = read_binary(path, offset, length, span, "INCBIN", symbols, location)?;
+			data.chunks(2).map(|pair| Byte::Definite(((pair[0] as u16) << 8) | pair.get(1).copied().unwrap_or(0) as u16)).collect()
+		},
+		Instruction::INCBINB(path, offset, length, span) => {
+			let data = read_binary(path, offset, length, span, "INCBINB", symbols, location)?;
+			data.iter().map(|byte| Byte::Definite(*byte as u16)).collect()
+		},
+		Instruction::CONST(name, value, span) => {
+			let value = fit(value.evaluate(&symbols.values, location)?, 16, value, "Value")?;
+			warnings.extend(symbols.define(name, value, SymbolKind::Constant, span)?);
 			vec!()
 		},
-		Instruction::MARK(name) => {
-			println!("Added constant {}, {}", name, byte_address);
-			constants.insert(name.to_owned(), byte_address);
+		Instruction::VAR(name, value, span) => {
+			let value = fit(value.evaluate(&symbols.values, location)?, 16, value, "Value")?;
+			warnings.extend(symbols.define(name, value, SymbolKind::Variable, span)?);
 			vec!()
 		},
-		Instruction::DATA(data)
-		|Instruction::DSTR(data) => {
-			data.into_iter().map(|value| Byte::Definite(*value)).collect()
+		Instruction::MARK(name, span) => {
+			if end > 0xFFFF {
+				return Err(Diagnostic::error(span.clone(), format!("Label \"{}\" is past the end of memory", name))
+					.with_hint(format!("It would be at {:#07X}, but the last address is 0xFFFF", end)));
+			}
+			warnings.extend(symbols.define(name, location.address, SymbolKind::Label, span)?);
+			vec!()
+		},
+		Instruction::DATA(data) => {
+			data.iter().map(|value| encode_literal(value, symbols, location)).collect::<Result<_, _>>()?
+		},
+		Instruction::DSTR(data, attribute) => encode_string(data, attribute, symbols, location)?,
+		Instruction::DSTRZ(data, attribute) => {
+			let mut bytes = encode_string(data, attribute, symbols, location)?;
+			bytes.push(Byte::Definite(0x0000));
+			bytes
+		},
+		Instruction::DSTRL(data, attribute) => {
+			let mut bytes = vec!(Byte::Definite(data.len() as u16));
+			bytes.extend(encode_string(data, attribute, symbols, location)?);
+			bytes
+		},
+		Instruction::DSTRP(data) => {
+			data.chunks(2).map(|pair| Byte::Definite((pair[0] << 8) | pair.get(1).copied().unwrap_or(0))).collect()
 		},
 		
 		Instruction::HALT	=> vec!(Byte::Definite(0x0000)),
 		Instruction::NOP	=> vec!(Byte::Definite(0x0001)),
 		Instruction::RET	=> vec!(Byte::Definite(0x0002)),
 		
-		Instruction::NEG	(target)	=> encode_one_op_instruction(0x0003, target),
-		Instruction::NOT	(target)	=> encode_one_op_instruction(0x000D, target),
-		Instruction::PUSH	(target)	=> encode_one_op_instruction(0x0017, target),
-		Instruction::POP	(target)	=> encode_one_op_instruction(0x0021, target),
-		Instruction::VPUSH	(target)	=> encode_one_op_instruction(0x002B, target),
-		Instruction::VPOP	(target)	=> encode_one_op_instruction(0x0035, target),
-		Instruction::CALL	(target)	=> encode_one_op_instruction(0x003F, target),
-		Instruction::JMP	(target)	=> encode_one_op_instruction(0x0049, target),
-		Instruction::JG		(target)	=> encode_one_op_instruction(0x0053, target),
-		Instruction::JNG	(target)	=> encode_one_op_instruction(0x005D, target),
-		Instruction::JL		(target)	=> encode_one_op_instruction(0x0067, target),
-		Instruction::JNL	(target)	=> encode_one_op_instruction(0x0071, target),
-		Instruction::JE		(target)	=> encode_one_op_instruction(0x007B, target),
-		Instruction::JNE	(target)	=> encode_one_op_instruction(0x0085, target),
-		Instruction::EXTI	(target)	=> encode_one_op_instruction(0x008F, target),
+		Instruction::NEG	(target)	=> encode_one_op_instruction(0x0003, target, symbols, location)?,
+		Instruction::NOT	(target)	=> encode_one_op_instruction(0x000D, target, symbols, location)?,
+		Instruction::PUSH	(target)	=> encode_one_op_instruction(0x0017, target, symbols, location)?,
+		Instruction::POP	(target)	=> encode_one_op_instruction(0x0021, target, symbols, location)?,
+		Instruction::VPUSH	(target)	=> encode_one_op_instruction(0x002B, target, symbols, location)?,
+		Instruction::VPOP	(target)	=> encode_one_op_instruction(0x0035, target, symbols, location)?,
+		Instruction::CALL	(target)	=> encode_one_op_instruction(0x003F, target, symbols, location)?,
+		Instruction::JMP	(target)	=> encode_one_op_instruction(0x0049, target, symbols, location)?,
+		Instruction::JG		(target)	=> encode_one_op_instruction(0x0053, target, symbols, location)?,
+		Instruction::JNG	(target)	=> encode_one_op_instruction(0x005D, target, symbols, location)?,
+		Instruction::JL		(target)	=> encode_one_op_instruction(0x0067, target, symbols, location)?,
+		Instruction::JNL	(target)	=> encode_one_op_instruction(0x0071, target, symbols, location)?,
+		Instruction::JE		(target)	=> encode_one_op_instruction(0x007B, target, symbols, location)?,
+		Instruction::JNE	(target)	=> encode_one_op_instruction(0x0085, target, symbols, location)?,
+		Instruction::EXTI	(target)	=> encode_one_op_instruction(0x008F, target, symbols, location)?,
 		
-		Instruction::ADD	(lhs, rhs)	=> encode_two_op_instruction(0x0099, lhs, rhs),
-		Instruction::SUB	(lhs, rhs)	=> encode_two_op_instruction(0x00F3, lhs, rhs),
-		Instruction::MUL	(lhs, rhs)	=> encode_two_op_instruction(0x014D, lhs, rhs),
-		Instruction::DIV	(lhs, rhs)	=> encode_two_op_instruction(0x01A7, lhs, rhs),
-		Instruction::MOD	(lhs, rhs)	=> encode_two_op_instruction(0x0201, lhs, rhs),
-		Instruction::SMUL	(lhs, rhs)	=> encode_two_op_instruction(0x025B, lhs, rhs),
-		Instruction::SDIV	(lhs, rhs)	=> encode_two_op_instruction(0x02B5, lhs, rhs),
-		Instruction::SMOD	(lhs, rhs)	=> encode_two_op_instruction(0x030F, lhs, rhs),
-		Instruction::AND	(lhs, rhs)	=> encode_two_op_instruction(0x0369, lhs, rhs),
-		Instruction::OR		(lhs, rhs)	=> encode_two_op_instruction(0x03C3, lhs, rhs),
-		Instruction::XOR	(lhs, rhs)	=> encode_two_op_instruction(0x041D, lhs, rhs),
-		Instruction::SHL	(lhs, rhs)	=> encode_two_op_instruction(0x0477, lhs, rhs),
-		Instruction::SHR	(lhs, rhs)	=> encode_two_op_instruction(0x04D1, lhs, rhs),
-		Instruction::SAR	(lhs, rhs)	=> encode_two_op_instruction(0x052B, lhs, rhs),
-		Instruction::SET	(lhs, rhs)	=> encode_two_op_instruction(0x0585, lhs, rhs),
-		Instruction::GET	(lhs, rhs)	=> encode_two_op_instruction(0x05DF, lhs, rhs),
-		Instruction::SWAP	(lhs, rhs)	=> encode_two_op_instruction(0x0639, lhs, rhs),
-		Instruction::CMP	(lhs, rhs)	=> encode_two_op_instruction(0x0693, lhs, rhs),
+		Instruction::ADD	(lhs, rhs)	=> encode_two_op_instruction(0x0099, lhs, rhs, span, symbols, location)?,
+		Instruction::SUB	(lhs, rhs)	=> encode_two_op_instruction(0x00F3, lhs, rhs, span, symbols, location)?,
+		Instruction::MUL	(lhs, rhs)	=> encode_two_op_instruction(0x014D, lhs, rhs, span, symbols, location)?,
+		Instruction::DIV	(lhs, rhs)	=> encode_two_op_instruction(0x01A7, lhs, rhs, span, symbols, location)?,
+		Instruction::MOD	(lhs, rhs)	=> encode_two_op_instruction(0x0201, lhs, rhs, span, symbols, location)?,
+		Instruction::SMUL	(lhs, rhs)	=> encode_two_op_instruction(0x025B, lhs, rhs, span, symbols, location)?,
+		Instruction::SDIV	(lhs, rhs)	=> encode_two_op_instruction(0x02B5, lhs, rhs, span, symbols, location)?,
+		Instruction::SMOD	(lhs, rhs)	=> encode_two_op_instruction(0x030F, lhs, rhs, span, symbols, location)?,
+		Instruction::AND	(lhs, rhs)	=> encode_two_op_instruction(0x0369, lhs, rhs, span, symbols, location)?,
+		Instruction::OR		(lhs, rhs)	=> encode_two_op_instruction(0x03C3, lhs, rhs, span, symbols, location)?,
+		Instruction::XOR	(lhs, rhs)	=> encode_two_op_instruction(0x041D, lhs, rhs, span, symbols, location)?,
+		Instruction::SHL	(lhs, rhs)	=> encode_two_op_instruction(0x0477, lhs, rhs, span, symbols, location)?,
+		Instruction::SHR	(lhs, rhs)	=> encode_two_op_instruction(0x04D1, lhs, rhs, span, symbols, location)?,
+		Instruction::SAR	(lhs, rhs)	=> encode_two_op_instruction(0x052B, lhs, rhs, span, symbols, location)?,
+		Instruction::SET	(lhs, rhs)	=> encode_two_op_instruction(0x0585, lhs, rhs, span, symbols, location)?,
+		Instruction::GET	(lhs, rhs)	=> encode_two_op_instruction(0x05DF, lhs, rhs, span, symbols, location)?,
+		Instruction::SWAP	(lhs, rhs)	=> encode_two_op_instruction(0x0639, lhs, rhs, span, symbols, location)?,
+		Instruction::CMP	(lhs, rhs)	=> encode_two_op_instruction(0x0693, lhs, rhs, span, symbols, location)?,
+	};
+	
+	match segments.last_mut() {
+		Some(segment) => segment.words.extend(bytes),
+		None => segments.push(Segment {words: bytes, ..Segment::new(0, None)}),
+	}
+	
+	Ok(warnings)
+}
+
+fn start_segment(segments: &mut Vec<Segment<Byte>>, origin: u32, span: &Span) {
+	match segments.last_mut() {
+		Some(segment) if segment.words.is_empty() => *segment = Segment::new(origin, Some(span.clone())),
+		_ => segments.push(Segment::new(origin, Some(span.clone()))),
+	}
+}
+
+fn evaluate_count(count: &Expression, span: &Span, keyword: &str, symbols: &Symbols, location: Location) -> Result<u16, Diagnostic> {
+	let value = count.evaluate(&symbols.values, location)?;
+	
+	if (0..=0xFFFF).contains(&value) {
+		Ok(value as u16)
+	} else {
+		Err(Diagnostic::error(span.clone(), format!("{} count must be between 0 and 65535, got {}", keyword, value)))
+	}
+}
+
+// Offsets and lengths are in bytes, whichever way the data ends up being packed into words.
+fn read_binary(path: &Path, offset: &Option<Expression>, length: &Option<Expression>, span: &Span, keyword: &str, symbols: &Symbols, location: Location) -> Result<Vec<u8>, Diagnostic> {
+	let data = match fs::read(path) {
+		Ok(data) => data,
+		Err(err) => return Err(Diagnostic::error(span.clone(), format!("{} could not read \"{}\": {}", keyword, path.display(), err))),
+	};
+	
+	let evaluate = |expression: &Expression| -> Result<usize, Diagnostic> {
+		let value = expression.evaluate(&symbols.values, location)?;
+		if value < 0 {
+			return Err(Diagnostic::error(span.clone(), format!("{} offsets and lengths can't be negative, got {}", keyword, value)));
+		}
+		Ok(value as usize)
+	};
+	
+	let start = match offset {
+		Some(offset) => evaluate(offset)?,
+		None => 0,
+	};
+	let end = match length {
+		Some(length) => start + evaluate(length)?,
+		None => data.len().max(start),
+	};
+	
+	if end > data.len() {
+		return Err(Diagnostic::error(span.clone(), format!("{} range {}..{} runs past the end of \"{}\"", keyword, start, end, path.display()))
+			.with_hint(format!("The file is {} bytes long", data.len())));
+	}
+	
+	Ok(data[start..end].to_vec())
+}
+
+pub fn encode_identifiers(symbols: &Symbols, segments: &[Segment<Byte>]) -> Result<Vec<Segment<u16>>, Vec<Diagnostic>> {
+	let mut encoded_segments: Vec<Segment<u16>> = vec!();
+	let mut diagnostics = vec!();
+	
+	for segment in segments.iter() {
+		let mut encoded_file = vec!();
 		
-		_ => panic!("Not implemented"),
-	}
-}
-
-pub fn encode_identifiers(constants: &HashMap<String, u16>, partially_encoded_file: &Vec<Byte>) -> Vec<u16> {
-	let mut encoded_file = vec!();
-	
-	for byte in partially_encoded_file.into_iter() {
-		match byte {
-			Byte::Definite(value) => encoded_file.push(*value),
-			Byte::Identifier(name) => {
-				match constants.get_key_value(name) {
-					Some((_, value)) => encoded_file.push(*value),
-					None => panic!("Invalid identifier: \"{}\"", name),
-				}
-			},
-			Byte::FromMemWithIdentifier(from_mem) => match from_mem {
-				FromMem::RegisterLiteral(reg, literal) => match literal {
-					Literal::Identifier(subtract, name) => {
-						let offset = match constants.get_key_value(name) {
-							Some((_, value)) => if *subtract {-(*value as i16) as u16} else {*value},
-							None => panic!("Invalid identifier: \"{}\"", name),
-						};
-						
-						encoded_file.push(register_offset(&reg) | (offset << 4))
-					},
-					_ => panic!("Theoretically unreachable state."),
-				},
-				FromMem::TwoRegisterLiteral(lhs, reg_subtract, rhs, literal) => match literal {
-					Literal::Identifier(subtract, name) => {
-						let offset = match constants.get_key_value(name) {
-							Some((_, value)) => if *subtract {-(*value as i16) as u16} else {*value},
-							None => panic!("Invalid identifier: \"{}\"", name),
-						};
-						
-						encoded_file.push(encode_two_register_from_mem(&lhs, &rhs, *reg_subtract) | offset << 8)
-					},
-					_ => panic!("Theoretically unreachable state."),
+		for byte in segment.words.iter() {
+			let value = match byte {
+				Byte::Definite(value) => Ok(*value),
+				Byte::Expression(expression, location) => {
+					expression.evaluate(&symbols.values, *location).and_then(|value| fit(value, 16, expression, "Value"))
 				},
-				_ => panic!("Theoretically unreachable state."),
+				Byte::FromMemWithExpression(from_mem, location) => encode_from_mem(from_mem, symbols, *location),
+			};
+			
+			match value {
+				Ok(value) => encoded_file.push(value),
+				Err(diagnostic) => diagnostics.push(diagnostic),
 			}
+		};
+		
+		if !segment.words.is_empty() {
+			encoded_segments.push(Segment {words: encoded_file, span: segment.span.clone(), ..Segment::new(segment.origin, None)});
 		}
 	};
 	
-	encoded_file
+	diagnostics.extend(check_layout(&mut encoded_segments));
+	
+	if diagnostics.is_empty() {Ok(encoded_segments)} else {Err(diagnostics)}
 }
 
-fn encode_one_op_instruction(base_op: u16, target: &Target) -> Vec<Byte> {
-	let mut data = vec!(Byte::Definite(base_op + target_offset(&target)));
+// Sorts the segments into address order, making sure none of them overlap or run off the end of memory.
+fn check_layout(segments: &mut [Segment<u16>]) -> Vec<Diagnostic> {
+	let mut diagnostics = vec!();
 	
-	match target {
-		Target::Register(_) => (),
-		Target::Literal(literal) => match literal {
-			Literal::Number(value) => data.push(Byte::Definite(*value)),
-			Literal::Identifier(_, identifier) => data.push(Byte::Identifier(identifier.clone())),
+	segments.sort_by_key(|segment| segment.origin);
+	
+	for (index, segment) in segments.iter().enumerate() {
+		let span = segment.span.clone().unwrap_or_default();
+		
+		if segment.end() > 0x1_0000 {
+			diagnostics.push(Diagnostic::error(span.clone(), format!("Segment at {:#06X} runs past the end of memory", segment.origin))
+				.with_hint(format!("It is {} words long, which would end at {:#07X}", segment.words.len(), segment.end())));
+		}
+		
+		if let Some(next) = segments.get(index + 1) {
+			if segment.end() > next.origin {
+				diagnostics.push(Diagnostic::error(next.span.clone().unwrap_or_default(), format!("Segment at {:#06X} overlaps the segment at {:#06X}", next.origin, segment.origin))
+					.with_hint(format!("The earlier segment ends at {:#06X}", segment.end())));
+			}
+		}
+	}
+	
+	diagnostics
+}
+
+fn encode_string(data: &[u16], attribute: &Option<Expression>, symbols: &Symbols, location: Location) -> Result<Vec<Byte>, Diagnostic> {
+	let bytes = match attribute {
+		Some(attribute) => {
+			let attribute = fit(attribute.evaluate(&symbols.values, location)?, 8, attribute, "String attribute")?;
+			data.iter().map(|value| Byte::Definite((*value & 0x00FF) | (attribute << 8))).collect()
 		},
-		Target::FromMem(from_mem) => data.push(from_mem_partial_encode(&from_mem)),
+		None => data.iter().map(|value| Byte::Definite(*value)).collect(),
 	};
 	
-	data
+	Ok(bytes)
+}
+
+// Anything that can't be worked out yet is left for encode_identifiers, once every MARK has an address.
+// VARs might have changed by then, so their current values are put in place first.
+fn encode_literal(expression: &Expression, symbols: &Symbols, location: Location) -> Result<Byte, Diagnostic> {
+	match expression.evaluate(&symbols.values, location) {
+		Ok(value) => Ok(Byte::Definite(fit(value, 16, expression, "Value")?)),
+		Err(_) => Ok(Byte::Expression(freeze_variables(expression, symbols), location)),
+	}
 }
 
-fn encode_two_op_instruction(base_op: u16, lhs: &Target, rhs: &Target) -> Vec<Byte> {
-	let mut data = vec!(Byte::Definite(base_op + target_offset(&lhs) + target_offset(&rhs) * 10));
+// Words and string attributes take anything that fits either signed or unsigned, so an attribute can be
+// -128 up to 255. Symbols only hold 16 bit words, which makes 0xFFFF the same -1 as `-1` itself for the
+// narrower fields.
+fn fit(value: i32, bits: u32, expression: &Expression, operand: &str) -> Result<u16, Diagnostic> {
+	fit_range(value, bits, -(1 << (bits - 1)), (1 << bits) - 1, expression, operand)
+}
+
+// Memory offsets are added as two's complement, so a 12 bit offset is only -2048 up to 2047.
+fn fit_signed(value: i32, bits: u32, expression: &Expression, operand: &str) -> Result<u16, Diagnostic> {
+	fit_range(value, bits, -(1 << (bits - 1)), (1 << (bits - 1)) - 1, expression, operand)
+}
+
+fn fit_range(value: i32, bits: u32, min: i32, max: i32, expression: &Expression, operand: &str) -> Result<u16, Diagnostic> {
+	let signed = if bits < 16 && (0x8000..=0xFFFF).contains(&value) {value - 0x1_0000} else {value};
 	
-	match lhs {
-		Target::Register(_) => (),
-		Target::Literal(literal) => match literal {
-			Literal::Number(value) => data.push(Byte::Definite(*value)),
-			Literal::Identifier(_, identifier) => data.push(Byte::Identifier(identifier.clone())),
+	if (min..=max).contains(&signed) {
+		Ok((signed & ((1 << bits) - 1)) as u16)
+	} else {
+		Err(Diagnostic::error(expression.span(), format!("{} {} doesn't fit in {} bits", operand, value, bits))
+			.with_hint(format!("{}s have to be between {} and {}", operand, min, max)))
+	}
+}
+
+fn freeze_variables(expression: &Expression, symbols: &Symbols) -> Expression {
+	match expression {
+		Expression::Identifier(name, span) if symbols.is_variable(name) => Expression::Number(symbols.values[name], span.clone()),
+		Expression::Unary(operator, operand, span) => Expression::Unary(*operator, Box::new(freeze_variables(operand, symbols)), span.clone()),
+		Expression::Binary(operator, lhs, rhs, span) => {
+			Expression::Binary(*operator, Box::new(freeze_variables(lhs, symbols)), Box::new(freeze_variables(rhs, symbols)), span.clone())
 		},
-		Target::FromMem(from_mem) => data.push(from_mem_partial_encode(&from_mem)),
-	};
+		_ => expression.clone(),
+	}
+}
+
+// Operands that are out of range are errors straight away, but ones that can't be worked out yet are left for later.
+fn encode_operand(target: &Target, symbols: &Symbols, location: Location) -> Result<Option<Byte>, Diagnostic> {
+	Ok(match target {
+		Target::Register(_) => None,
+		Target::Literal(literal) => Some(encode_literal(literal, symbols, location)?),
+		Target::FromMem(from_mem) => Some(match encode_from_mem(from_mem, symbols, location) {
+			Ok(value) => Byte::Definite(value),
+			Err(diagnostic) if from_mem_evaluates(from_mem, symbols, location) => return Err(diagnostic),
+			Err(_) => Byte::FromMemWithExpression(match from_mem {
+				FromMem::RegisterLiteral(register, offset) => FromMem::RegisterLiteral(register.clone(), freeze_variables(offset, symbols)),
+				FromMem::TwoRegisterLiteral(first, subtract, second, offset) => {
+					FromMem::TwoRegisterLiteral(first.clone(), *subtract, second.clone(), freeze_variables(offset, symbols))
+				},
+				from_mem => from_mem.clone(),
+			}, location),
+		}),
+	})
+}
+
+fn from_mem_evaluates(from_mem: &FromMem, symbols: &Symbols, location: Location) -> bool {
+	match from_mem {
+		FromMem::RegisterLiteral(_, offset) | FromMem::TwoRegisterLiteral(_, _, _, offset) => offset.evaluate(&symbols.values, location).is_ok(),
+		_ => true,
+	}
+}
+
+fn encode_one_op_instruction(base_op: u16, target: &Target, symbols: &Symbols, location: Location) -> Result<Vec<Byte>, Diagnostic> {
+	let mut data = vec!(Byte::Definite(base_op + target_offset(target)));
 	
-	match rhs {
-		Target::Register(_) => (),
-		Target::Literal(literal) => match literal {
-			Literal::Number(value) => data.push(Byte::Definite(*value)),
-			Literal::Identifier(_, identifier) => data.push(Byte::Identifier(identifier.clone())),
-		},
-		Target::FromMem(_) => panic!("Encoding error: Second operand cannot be FromMem!")
-	};
+	data.extend(encode_operand(target, symbols, location)?);
 	
-	data
+	Ok(data)
+}
+
+// The parser never reads memory as the second operand, but instructions built through the library can.
+fn encode_two_op_instruction(base_op: u16, lhs: &Target, rhs: &Target, span: &Span, symbols: &Symbols, location: Location) -> Result<Vec<Byte>, Diagnostic> {
+	if let Target::FromMem(_) = rhs {
+		return Err(Diagnostic::error(span.clone(), "The second operand can't be read from memory")
+			.with_hint("Only the first operand of an instruction can be in square brackets"));
+	}
+	
+	let mut data = vec!(Byte::Definite(base_op + target_offset(lhs) + target_offset(rhs) * 10));
+	data.extend(encode_operand(lhs, symbols, location)?);
+	data.extend(encode_operand(rhs, symbols, location)?);
+	
+	Ok(data)
 }
 
 fn target_offset(target: &Target) -> u16 {
@@ -172,25 +408,21 @@ fn register_offset(register: &Register) -> u16 {
 	}
 }
 
-fn from_mem_partial_encode(data: &FromMem) -> Byte {
-	match data {
-		FromMem::Register(reg) => Byte::Definite(register_offset(reg)),
-		FromMem::RegisterLiteral(reg, literal) => match literal {
-			Literal::Number(value) => {
-				let value = (*value as i16 % (1 << 12)) as u16;
-				Byte::Definite(register_offset(reg) | (value << 4))
-			}
-			Literal::Identifier(_,_) => Byte::FromMemWithIdentifier((*data).clone()),
-		}
-		FromMem::TwoRegister(left_reg, subtract, right_reg) => Byte::Definite(encode_two_register_from_mem(left_reg, right_reg, *subtract)),
-		FromMem::TwoRegisterLiteral(left_reg, subtract, right_reg, literal) => match literal {
-			Literal::Number(value) => {
-				let value = (*value as i16 % (1 << 8)) as u16;
-				Byte::Definite(encode_two_register_from_mem(left_reg, right_reg, *subtract) | (value << 8))
-			}
-			Literal::Identifier(_,_) => Byte::FromMemWithIdentifier((*data).clone()),
-		}
-	}
+fn encode_from_mem(data: &FromMem, symbols: &Symbols, location: Location) -> Result<u16, Diagnostic> {
+	let value = match data {
+		FromMem::Register(reg) => register_offset(reg),
+		FromMem::RegisterLiteral(reg, offset) => {
+			let offset = fit_signed(offset.evaluate(&symbols.values, location)?, 12, offset, "Memory offset")?;
+			register_offset(reg) | (offset << 4)
+		},
+		FromMem::TwoRegister(left_reg, subtract, right_reg) => encode_two_register_from_mem(left_reg, right_reg, *subtract),
+		FromMem::TwoRegisterLiteral(left_reg, subtract, right_reg, offset) => {
+			let offset = fit_signed(offset.evaluate(&symbols.values, location)?, 8, offset, "Two register memory offset")?;
+			encode_two_register_from_mem(left_reg, right_reg, *subtract) | (offset << 8)
+		},
+	};
+	
+	Ok(value)
 }
 
 fn encode_two_register_from_mem(lhs: &Register, rhs: &Register, subtract: bool) -> u16 {
@@ -198,4 +430,159 @@ fn encode_two_register_from_mem(lhs: &Register, rhs: &Register, subtract: bool)
 	| 0b1000 // Two register indicator bit
 	| (register_offset(rhs) << 4) // Right register ID
 	| if subtract {0b1000_0000} else {0} // Register operation
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+	use crate::lexer::TokenStream;
+	use crate::source::Sources;
+	
+	fn assemble(source: &str) -> Result<Vec<u16>, Vec<Diagnostic>> {
+		let mut sources = Sources::new(vec!());
+		let file = sources.add("test.a19".to_owned(), None, source.to_owned());
+		let mut lex = TokenStream::new(sources.tokens(file), file);
+		let mut context = Context::new(sources);
+		let mut symbols = Symbols::new();
+		let mut segments = vec!(Segment::new(0, None));
+		
+		while let Some((instruction, span)) = parse(&mut lex, &mut context, &symbols.values, location(&segments)).map_err(|diagnostic| vec!(diagnostic))? {
+			partially_encode(&instruction, &span, &mut symbols, &mut segments).map_err(|diagnostic| vec!(diagnostic))?;
+		}
+		
+		let segments = encode_identifiers(&symbols, &segments)?;
+		Ok(segments.into_iter().flat_map(|segment| segment.words).collect())
+	}
+	
+	// The operand of `GET <operand> A`, which is always the word after the opcode.
+	fn from_mem(operand: &str) -> u16 {
+		let words = assemble(&format!("CONST IDENT 3\nGET {} A\nMARK Later", operand)).unwrap();
+		assert_eq!(words[0], 0x05E8);
+		words[1]
+	}
+	
+	#[test]
+	fn register() {
+		assert_eq!(from_mem("[A]"), 0x0000);
+		assert_eq!(from_mem("[SP]"), 0x0004);
+	}
+	
+	#[test]
+	fn register_literal() {
+		assert_eq!(from_mem("[A+4]"), 0x0040);
+		assert_eq!(from_mem("[A-4]"), 0xFFC0);
+		assert_eq!(from_mem("[B+IDENT]"), 0x0031);
+		assert_eq!(from_mem("[A-IDENT]"), 0xFFD0);
+		assert_eq!(from_mem("[A-4+2]"), 0xFFE0);
+		assert_eq!(from_mem("[A-(4+2)]"), 0xFFA0);
+		assert_eq!(from_mem("[A+2047]"), 0x7FF0);
+		assert_eq!(from_mem("[A-2048]"), 0x8000);
+	}
+	
+	#[test]
+	fn register_literal_forward_reference() {
+		assert_eq!(from_mem("[A+Later]"), 0x0020);
+		assert_eq!(from_mem("[A-Later]"), 0xFFE0);
+	}
+	
+	#[test]
+	fn two_register() {
+		assert_eq!(from_mem("[A+B]"), 0x0018);
+		assert_eq!(from_mem("[A-B]"), 0x0098);
+		assert_eq!(from_mem("[SP+C]"), 0x002C);
+	}
+	
+	#[test]
+	fn two_register_literal() {
+		assert_eq!(from_mem("[A+B+4]"), 0x0418);
+		assert_eq!(from_mem("[A+B-4]"), 0xFC18);
+		assert_eq!(from_mem("[A-B+4]"), 0x0498);
+		assert_eq!(from_mem("[A-B-4]"), 0xFC98);
+		assert_eq!(from_mem("[A-B-IDENT]"), 0xFD98);
+		assert_eq!(from_mem("[A+B-Later]"), 0xFE18);
+		assert_eq!(from_mem("[A+B+127]"), 0x7F18);
+		assert_eq!(from_mem("[A+B-128]"), 0x8018);
+	}
+	
+	#[test]
+	fn out_of_range_offsets() {
+		assert!(assemble("GET [A+2048] A").is_err());
+		assert!(assemble("GET [A+4095] A").is_err());
+		assert!(assemble("GET [A-2049] A").is_err());
+		assert!(assemble("GET [A+B+128] A").is_err());
+		assert!(assemble("GET [A+B+200] A").is_err());
+		assert!(assemble("GET [A+B-129] A").is_err());
+		assert!(assemble("GET [A+B+Later] A\nRESERVE 300\nMARK Later").is_err());
+	}
+	
+	#[test]
+	fn complement() {
+		assert_eq!(assemble("DATA ~0x8000").unwrap(), vec!(0x7FFF));
+		assert_eq!(assemble("CONST F 0xFF00\nDATA ~F").unwrap(), vec!(0x00FF));
+		assert_eq!(assemble("DATA ~0 ~-1").unwrap(), vec!(0xFFFF, 0x0000));
+		assert_eq!(from_mem("[A+~0]"), 0xFFF0);
+	}
+	
+	#[test]
+	fn constant_range() {
+		assert_eq!(assemble("CONST X -32768\nCONST Y 0xFFFF\nDATA X Y").unwrap(), vec!(0x8000, 0xFFFF));
+		assert!(assemble("CONST X -32769").is_err());
+		assert!(assemble("CONST X 0xFFFF*16").is_err());
+		assert!(assemble("VAR X 0x10000").is_err());
+	}
+	
+	#[test]
+	fn macro_labels_keep_scope() {
+		let source = "MACRO WAIT\nLOCAL spin\nMARK spin\nJMP spin\nENDM\nMARK Func\nWAIT\nMARK .after\nJMP Func.after";
+		assert_eq!(assemble(source).unwrap().len(), 4);
+	}
+	
+	#[test]
+	fn origin_range() {
+		assert_eq!(assemble("ORG 0xFFFF\nDATA 1").unwrap(), vec!(1));
+		assert!(assemble("ORG 0xFFFF+3\nDATA 1").is_err());
+		assert!(assemble("ORG -1").is_err());
+	}
+	
+	#[test]
+	fn reserve_range() {
+		assert_eq!(assemble("ORG 0xFFF0\nDATA 1\nRESERVE 0xF").unwrap(), vec!(1));
+		assert!(assemble("RESERVE 0xFFFF\nRESERVE 5\nDATA 1").is_err());
+		assert!(assemble("ORG 0xFFF0\nRESERVE 0x11").is_err());
+		assert!(assemble("ORG 0xFFF0\nDATA 1\nRESERVE 0xF\nDATA 2").is_err());
+		assert!(assemble("ORG 0xFFF0\nDATA 1\nRESERVE 0xF\nMARK End").is_err());
+		assert!(assemble("ORG 0xFFFF\nDATA 1\nMARK End").is_err());
+	}
+	
+	#[test]
+	fn memory_as_second_operand() {
+		let instruction = Instruction::ADD(Target::Register(Register::A), Target::FromMem(FromMem::Register(Register::B)));
+		let mut segments = vec!(Segment::new(0, None));
+		assert!(partially_encode(&instruction, &Span::new(0, 0, 0), &mut Symbols::new(), &mut segments).is_err());
+	}
+	
+	#[test]
+	fn string_attribute() {
+		assert_eq!(assemble("DSTR \"hi\" 0xFF").unwrap(), vec!(0xFF68, 0xFF69));
+		assert_eq!(assemble("DSTR \"hi\" 0x80").unwrap(), vec!(0x8068, 0x8069));
+		assert_eq!(assemble("DSTR \"hi\" -1").unwrap(), vec!(0xFF68, 0xFF69));
+		assert!(assemble("DSTR \"hi\" 0x100").is_err());
+	}
+	
+	#[test]
+	fn spaced_signs() {
+		assert_eq!(assemble("DATA 1 -2 3 - 1 (1 -2)").unwrap(), vec!(1, 0xFFFE, 2, 0xFFFF));
+		assert_eq!(assemble("CONST X 5 -1\nDATA X").unwrap(), vec!(4));
+		assert_eq!(assemble("ORG 0x10 +2\nDATA $").unwrap(), vec!(0x12));
+		assert_eq!(assemble("IF 1 -1\nDATA 1\nELSE\nDATA 2\nENDIF").unwrap(), vec!(2));
+		assert_eq!(assemble("REPT 1 +1\nDATA 7\nENDR").unwrap(), vec!(7, 7));
+		assert_eq!(assemble("RESERVE 2 -1\nDATA $").unwrap(), vec!(1));
+	}
+	
+	#[test]
+	fn malformed() {
+		assert!(assemble("GET [A+B+C] A").is_err());
+		assert!(assemble("GET [A+4+B] A").is_err());
+		assert!(assemble("GET [4+A] A").is_err());
+	}
 }
\ No newline at end of file
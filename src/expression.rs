@@ -0,0 +1,221 @@
+use std::collections::HashMap;
+use crate::diagnostic::Diagnostic;
+use crate::source::Span;
+
+#[derive(Clone, Copy)]
+#[derive(Debug)]
+#[derive(PartialEq)]
+pub enum UnaryOperator {
+	Negate,
+	Not,
+	LogicalNot,
+}
+
+#[derive(Clone, Copy)]
+#[derive(Debug)]
+#[derive(PartialEq)]
+pub enum BinaryOperator {
+	Add,
+	Subtract,
+	Multiply,
+	Divide,
+	Modulo,
+	And,
+	Or,
+	Xor,
+	ShiftLeft,
+	ShiftRight,
+	Equal,
+	NotEqual,
+	Less,
+	LessEqual,
+	Greater,
+	GreaterEqual,
+	LogicalAnd,
+	LogicalOr,
+}
+
+#[derive(Clone)]
+#[derive(Debug)]
+pub enum Expression {
+	Number(u16, Span),
+	Identifier(String, Span),
+	// How many anonymous labels back (negative) or forward (positive) to look, until `resolve_labels` names the label.
+	Anonymous(i32, Span),
+	CurrentAddress(Span),
+	SectionStart(Span),
+	Unary(UnaryOperator, Box<Expression>, Span),
+	Binary(BinaryOperator, Box<Expression>, Box<Expression>, Span),
+}
+
+// Where the instruction an expression belongs to sits, for `$` and `$$`.
+#[derive(Clone, Copy)]
+#[derive(Debug)]
+pub struct Location {
+	pub address: u16,
+	pub section_start: u16,
+}
+
+impl UnaryOperator {
+	pub fn from_symbol(symbol: &str) -> Option<UnaryOperator> {
+		match symbol {
+			"-" => Some(UnaryOperator::Negate),
+			"~" => Some(UnaryOperator::Not),
+			"!" => Some(UnaryOperator::LogicalNot),
+			_ => None,
+		}
+	}
+}
+
+impl BinaryOperator {
+	pub fn from_symbol(symbol: &str) -> Option<BinaryOperator> {
+		match symbol {
+			"+"		=> Some(BinaryOperator::Add),
+			"-"		=> Some(BinaryOperator::Subtract),
+			"*"		=> Some(BinaryOperator::Multiply),
+			"/"		=> Some(BinaryOperator::Divide),
+			"%"		=> Some(BinaryOperator::Modulo),
+			"&"		=> Some(BinaryOperator::And),
+			"|"		=> Some(BinaryOperator::Or),
+			"^"		=> Some(BinaryOperator::Xor),
+			"<<"	=> Some(BinaryOperator::ShiftLeft),
+			">>"	=> Some(BinaryOperator::ShiftRight),
+			"=="	=> Some(BinaryOperator::Equal),
+			"!="	=> Some(BinaryOperator::NotEqual),
+			"<"		=> Some(BinaryOperator::Less),
+			"<="	=> Some(BinaryOperator::LessEqual),
+			">"		=> Some(BinaryOperator::Greater),
+			">="	=> Some(BinaryOperator::GreaterEqual),
+			"&&"	=> Some(BinaryOperator::LogicalAnd),
+			"||"	=> Some(BinaryOperator::LogicalOr),
+			_ => None,
+		}
+	}
+	
+	// Follows C, so that expressions copied from elsewhere mean the same thing here.
+	pub fn precedence(&self) -> u8 {
+		match self {
+			BinaryOperator::LogicalOr => 0,
+			BinaryOperator::LogicalAnd => 1,
+			BinaryOperator::Or => 2,
+			BinaryOperator::Xor => 3,
+			BinaryOperator::And => 4,
+			BinaryOperator::Equal | BinaryOperator::NotEqual => 5,
+			BinaryOperator::Less | BinaryOperator::LessEqual | BinaryOperator::Greater | BinaryOperator::GreaterEqual => 6,
+			BinaryOperator::ShiftLeft | BinaryOperator::ShiftRight => 7,
+			BinaryOperator::Add | BinaryOperator::Subtract => 8,
+			BinaryOperator::Multiply | BinaryOperator::Divide | BinaryOperator::Modulo => 9,
+		}
+	}
+}
+
+impl Expression {
+	// From the first token of the expression to the last, leaving out any parentheses around the outside.
+	pub fn span(&self) -> Span {
+		match self {
+			Expression::Number(_, span)
+			| Expression::Identifier(_, span)
+			| Expression::Anonymous(_, span)
+			| Expression::CurrentAddress(span)
+			| Expression::SectionStart(span) => span.clone(),
+			Expression::Unary(_, operand, span) => span.to(&operand.span()),
+			Expression::Binary(_, lhs, rhs, _) => lhs.span().to(&rhs.span()),
+		}
+	}
+	
+	// Gives local labels their full name, and anonymous ones the name of the `MARK :` they point to.
+	// `anonymous` is how many anonymous labels have been marked so far.
+	pub fn resolve_labels(&mut self, scope: Option<&str>, anonymous: usize) -> Result<(), Diagnostic> {
+		match self {
+			Expression::Identifier(name, span) => {
+				*name = qualify(name, scope, span)?;
+				Ok(())
+			},
+			Expression::Anonymous(offset, span) => {
+				let index = if *offset < 0 {anonymous as i64 + *offset as i64} else {anonymous as i64 + *offset as i64 - 1};
+				if index < 0 {
+					return Err(Diagnostic::error(span.clone(), "There aren't enough anonymous labels before this one")
+						.with_hint("Anonymous labels are defined with \"MARK :\""));
+				}
+				*self = Expression::Identifier(anonymous_name(index as usize), span.clone());
+				Ok(())
+			},
+			Expression::Unary(_, operand, _) => operand.resolve_labels(scope, anonymous),
+			Expression::Binary(_, lhs, rhs, _) => {
+				lhs.resolve_labels(scope, anonymous)?;
+				rhs.resolve_labels(scope, anonymous)
+			},
+			_ => Ok(()),
+		}
+	}
+	
+	// Values are worked out in 32 bits and only cut down to a word once they're encoded,
+	// so intermediate results like `0xFFFF + 1 - 1` don't wrap along the way.
+	pub fn evaluate(&self, constants: &HashMap<String, u16>, location: Location) -> Result<i32, Diagnostic> {
+		match self {
+			Expression::Number(value, _) => Ok(*value as i32),
+			Expression::CurrentAddress(_) => Ok(location.address as i32),
+			Expression::SectionStart(_) => Ok(location.section_start as i32),
+			Expression::Identifier(name, span) => match constants.get(name) {
+				Some(value) => Ok(*value as i32),
+				None if name.starts_with(':') => Err(Diagnostic::error(span.clone(), "There aren't enough anonymous labels after this one")
+					.with_hint("Anonymous labels are defined with \"MARK :\"")),
+				None => Err(Diagnostic::error(span.clone(), format!("Invalid identifier: \"{}\"", name))
+					.with_hint("Identifiers must be defined with CONST, VAR or MARK, and CONST and VAR values can only use symbols defined above them")),
+			},
+			Expression::Anonymous(_, span) => Err(Diagnostic::error(span.clone(), "Anonymous label used before it was resolved")),
+			Expression::Unary(operator, operand, _) => {
+				let operand = operand.evaluate(constants, location)?;
+				Ok(match operator {
+					UnaryOperator::Negate => operand.wrapping_neg(),
+					// Words are 16 bits, so `~0xFF00` is 0x00FF rather than a negative number that doesn't fit in one.
+					UnaryOperator::Not => !operand & 0xFFFF,
+					UnaryOperator::LogicalNot => (operand == 0) as i32,
+				})
+			},
+			Expression::Binary(operator, lhs, rhs, span) => {
+				let lhs = lhs.evaluate(constants, location)?;
+				let rhs = rhs.evaluate(constants, location)?;
+				match operator {
+					BinaryOperator::Add => Ok(lhs.wrapping_add(rhs)),
+					BinaryOperator::Subtract => Ok(lhs.wrapping_sub(rhs)),
+					BinaryOperator::Multiply => Ok(lhs.wrapping_mul(rhs)),
+					BinaryOperator::Divide | BinaryOperator::Modulo if rhs == 0 => {
+						Err(Diagnostic::error(span.clone(), "Division by zero in expression"))
+					},
+					BinaryOperator::Divide => Ok(lhs.wrapping_div(rhs)),
+					BinaryOperator::Modulo => Ok(lhs.wrapping_rem(rhs)),
+					BinaryOperator::And => Ok(lhs & rhs),
+					BinaryOperator::Or => Ok(lhs | rhs),
+					BinaryOperator::Xor => Ok(lhs ^ rhs),
+					BinaryOperator::ShiftLeft => Ok(if (0..32).contains(&rhs) {lhs << rhs} else {0}),
+					BinaryOperator::ShiftRight => Ok(lhs >> rhs.clamp(0, 31)),
+					// Comparisons give 1 or 0, mostly for IF.
+					BinaryOperator::Equal => Ok((lhs == rhs) as i32),
+					BinaryOperator::NotEqual => Ok((lhs != rhs) as i32),
+					BinaryOperator::Less => Ok((lhs < rhs) as i32),
+					BinaryOperator::LessEqual => Ok((lhs <= rhs) as i32),
+					BinaryOperator::Greater => Ok((lhs > rhs) as i32),
+					BinaryOperator::GreaterEqual => Ok((lhs >= rhs) as i32),
+					BinaryOperator::LogicalAnd => Ok((lhs != 0 && rhs != 0) as i32),
+					BinaryOperator::LogicalOr => Ok((lhs != 0 || rhs != 0) as i32),
+				}
+			},
+		}
+	}
+}
+
+// Local labels are the ones starting with a dot, and belong to `scope`.
+pub fn qualify(name: &str, scope: Option<&str>, span: &Span) -> Result<String, Diagnostic> {
+	match scope {
+		_ if !name.starts_with('.') => Ok(name.to_owned()),
+		Some(scope) => Ok(format!("{}{}", scope, name)),
+		None => Err(Diagnostic::error(span.clone(), format!("Local label \"{}\" isn't inside any label", name))
+			.with_hint("Local labels start with a dot and belong to the last MARK whose name doesn't")),
+	}
+}
+
+// The name the nth anonymous label is stored under. Source can't contain a `:` in a name, so these never clash.
+pub fn anonymous_name(index: usize) -> String {
+	format!(":{}", index)
+}
\ No newline at end of file
@@ -1,11 +1,34 @@
 
 #[derive(Clone)]
 #[derive(Debug)]
+#[derive(PartialEq)]
 pub enum Keyword {
 	CONST,
+	VAR,
 	MARK,
 	DATA,
 	DSTR,
+	DSTRZ,
+	DSTRL,
+	DSTRP,
+	ORG,
+	RESERVE,
+	FILL,
+	ALIGN,
+	INCBIN,
+	INCBINB,
+	INCLUDE,
+	MACRO,
+	ENDM,
+	LOCAL,
+	IF,
+	IFDEF,
+	IFNDEF,
+	ELIF,
+	ELSE,
+	ENDIF,
+	REPT,
+	ENDR,
 	
 	ADD,
 	SUB,
@@ -1,7 +1,11 @@
 use logos::*;
-use std::io::Write;
+use std::{
+	io::Write,
+	rc::Rc,
+};
 use termcolor::{self, Color, ColorSpec, StandardStream, WriteColor};
 use crate::keywords::{Keyword, Register};
+use crate::source::Span;
 
 
 fn get_register(lex: &mut Lexer<Token>) -> Option<Register> {
@@ -27,9 +31,31 @@ fn get_keyword(lex: &mut Lexer<Token>) -> Option<Keyword> {
 	
 	match slice.as_str() {
 		"CONST"		=>	Some(Keyword::CONST),
+		"VAR"		=>	Some(Keyword::VAR),
 		"MARK"		=>	Some(Keyword::MARK),
 		"DATA"		=>	Some(Keyword::DATA),
 		"DSTR"		=>	Some(Keyword::DSTR),
+		"DSTRZ"		=>	Some(Keyword::DSTRZ),
+		"DSTRL"		=>	Some(Keyword::DSTRL),
+		"DSTRP"		=>	Some(Keyword::DSTRP),
+		"ORG"		=>	Some(Keyword::ORG),
+		"RESERVE"	=>	Some(Keyword::RESERVE),
+		"FILL"		=>	Some(Keyword::FILL),
+		"ALIGN"		=>	Some(Keyword::ALIGN),
+		"INCBIN"	=>	Some(Keyword::INCBIN),
+		"INCBINB"	=>	Some(Keyword::INCBINB),
+		"INCLUDE"	=>	Some(Keyword::INCLUDE),
+		"MACRO"		=>	Some(Keyword::MACRO),
+		"ENDM"		=>	Some(Keyword::ENDM),
+		"LOCAL"		=>	Some(Keyword::LOCAL),
+		"IF"		=>	Some(Keyword::IF),
+		"IFDEF"		=>	Some(Keyword::IFDEF),
+		"IFNDEF"	=>	Some(Keyword::IFNDEF),
+		"ELIF"		=>	Some(Keyword::ELIF),
+		"ELSE"		=>	Some(Keyword::ELSE),
+		"ENDIF"		=>	Some(Keyword::ENDIF),
+		"REPT"		=>	Some(Keyword::REPT),
+		"ENDR"		=>	Some(Keyword::ENDR),
 		
 		"NEG"		=>	Some(Keyword::NEG),
 		"ADD"		=>	Some(Keyword::ADD),
@@ -75,56 +101,40 @@ fn get_keyword(lex: &mut Lexer<Token>) -> Option<Keyword> {
 	}
 }
 
-fn get_decimal_number (lex: &mut Lexer<Token>) -> Option<u16> {
-	let string = lex.slice()
-		.replace("_", "");
+// Literals that don't fit in a word come out as Error tokens, with the reason left in the lexer's extras
+// for `lex_file` to attach.
+fn get_number(lex: &mut Lexer<Token>, prefix: usize, radix: u32) -> Option<u16> {
+	let digits = lex.slice()[prefix..].replace('_', "");
 	
-	let value = u16::from_str_radix(string.as_str(), 10);
-	
-	match value {
+	match u16::from_str_radix(&digits, radix) {
 		Ok(value) => Some(value),
-		Err(err) => panic!("{}", err),
-	}
-}
-
-fn get_hexadecimal_number (lex: &mut Lexer<Token>) -> Option<u16> {
-	let string = &lex.slice()[2..]
-		.replace("_", "");
-	
-	let value = u16::from_str_radix(string.as_str(), 16);
-	
-	match value {
-		Ok(value) => Some(value),
-		Err(err) => panic!("{}", err),
-	}
-}
-
-fn get_binary_number (lex: &mut Lexer<Token>) -> Option<u16> {
-	let string = &lex.slice()[2..]
-		.replace("_", "");
-	
-	let value = u16::from_str_radix(string.as_str(), 2);
-	
-	match value {
-		Ok(value) => Some(value),
-		Err(err) => panic!("{}", err),
+		Err(_) => {
+			lex.extras = Some(format!("Number \"{}\" is too large to fit in a word", lex.slice()));
+			None
+		},
 	}
 }
 
 #[derive(Debug)]
 #[derive(Logos)]
 #[derive(Clone)]
+#[logos(extras = Option<String>)]
 pub enum Token {
 	#[error]
 	Error,
 	
+	// Never matched directly, see `get_number`.
+	InvalidNumber(String),
+	
 	#[regex("[ \t\n\r]+", logos::skip)]
 	Whitespace,
 	
 	#[regex(";.*", logos::skip)]
 	Comment,
 	
-	#[regex("(?i)((CONST)|(MARK)|(DATA)|(DSTR))", get_keyword)]
+	#[regex("(?i)((CONST)|(VAR)|(MARK)|(DATA)|(DSTR)|(DSTRZ)|(DSTRL)|(DSTRP))", get_keyword)]
+	#[regex("(?i)((ORG)|(RESERVE)|(FILL)|(ALIGN)|(INCBIN)|(INCBINB)|(INCLUDE)|(MACRO)|(ENDM)|(LOCAL))", get_keyword)]
+	#[regex("(?i)((IF)|(IFDEF)|(IFNDEF)|(ELIF)|(ELSE)|(ENDIF)|(REPT)|(ENDR))", get_keyword)]
 	#[regex("(?i)((NEG)|(ADD)|(SUB)|(MUL)|(DIV)|(MOD)|(SMUL)|(SDIV)|(SMOD))", get_keyword)]
 	#[regex("(?i)((NOT)|(AND)|(OR)|(XOR)|(SHL)|(SHR)|(SAR))", get_keyword)]
 	#[regex("(?i)((CMP)|(JG)|(JNG)|(JL)|(JNL)|(JE)|(JNE)|(JMP))", get_keyword)]
@@ -144,23 +154,202 @@ pub enum Token {
 	#[regex("\\]")]
 	CloseBracket,
 	
-	#[regex("\"(?:[^\"]|\\\\\")*\"")]
+	#[regex("\"(?:[^\"\\\\]|\\\\.)*\"")]
 	String,
 	
-	#[regex("(?i)(0x[0-9A-F][_0-9A-F]*)", get_hexadecimal_number)]
-	#[regex("(?i)(0b[01][_01]*)", get_binary_number)]
-	#[regex("([0-9][_0-9]*)", get_decimal_number)]
+	#[regex("'(?:[^'\\\\\n]|\\\\[^\n])+'")]
+	Character,
+	
+	#[regex("(?i)(0x[0-9A-F][_0-9A-F]*)", |lex| get_number(lex, 2, 16))]
+	#[regex("(?i)(0o[0-7][_0-7]*)", |lex| get_number(lex, 2, 8))]
+	#[regex("(?i)(0b[01][_01]*)", |lex| get_number(lex, 2, 2))]
+	#[regex("([0-9][_0-9]*)", |lex| get_number(lex, 0, 10))]
 	Number(u16),
 	
-	#[regex("[\\+-]")]
+	#[regex("\\$")]
+	CurrentAddress,
+	
+	#[regex("\\$\\$")]
+	SectionStart,
+	
+	#[regex(":(-+|\\++)?")]
+	AnonymousLabel,
+	
+	#[regex("\\(")]
+	OpenParen,
+	
+	#[regex("\\)")]
+	CloseParen,
+	
+	#[regex("[\\+\\-\\*/%&\\|\\^~!<>]")]
+	#[regex("(<<)|(>>)")]
+	#[regex("(==)|(!=)|(<=)|(>=)|(&&)|(\\|\\|)")]
 	Operator,
 	
-	#[regex("(?i)([_A-Z][_A-Z0-9]*\\.?)+", priority = 1)]
+	#[regex("(?i)\\.?([_A-Z][_A-Z0-9]*\\.?)+", priority = 1)]
 	Identifier,
 }
 
+// A token along with everything the parser needs to know about where it came from.
+#[derive(Clone)]
+#[derive(Debug)]
+pub struct Lexeme {
+	pub token: Token,
+	pub text: String,
+	pub span: Span,
+	pub spaced_before: bool,
+	pub spaced_after: bool,
+	pub line_start: bool,
+}
+
+pub fn lex_file(source: &str, file: usize) -> Vec<Lexeme> {
+	let mut lex = Token::lexer(source);
+	let mut lexemes: Vec<Lexeme> = vec!();
+	
+	while let Some(token) = lex.next() {
+		let token = match (token, lex.extras.take()) {
+			(Token::Error, Some(reason)) => Token::InvalidNumber(reason),
+			(token, _) => token,
+		};
+		let range = lex.span();
+		let previous_end = lexemes.last().map_or(0, |lexeme| lexeme.span.end);
+		lexemes.push(Lexeme {
+			token,
+			text: lex.slice().to_owned(),
+			span: Span::new(file, range.start, range.end),
+			spaced_before: source[..range.start].ends_with(|c: char| c.is_whitespace() || c == ','),
+			spaced_after: source[range.end..].starts_with(char::is_whitespace),
+			line_start: lexemes.is_empty() || source[previous_end..range.start].contains('\n'),
+		});
+	}
+	
+	lexemes
+}
+
+#[derive(Clone, Copy)]
+#[derive(Debug)]
+#[derive(PartialEq)]
+enum Origin {
+	File(usize),
+	Expansion(usize),
+}
+
+#[derive(Clone)]
+#[derive(Debug)]
+struct Frame {
+	lexemes: Rc<Vec<Lexeme>>,
+	position: usize,
+	origin: Origin,
+}
+
+// Reads tokens from a stack of files and macro expansions, so an INCLUDE or a macro invocation can splice
+// more tokens in at the current position.
+// Cloning is cheap, which is how the parser looks ahead.
+#[derive(Clone)]
+#[derive(Debug)]
+pub struct TokenStream {
+	frames: Vec<Frame>,
+	current: Option<Lexeme>,
+	end: Span,
+	consumed: usize,
+}
+
+impl TokenStream {
+	pub fn new(lexemes: Vec<Lexeme>, file: usize) -> TokenStream {
+		TokenStream {
+			frames: vec!(Frame {lexemes: Rc::new(lexemes), position: 0, origin: Origin::File(file)}),
+			current: None,
+			end: Span::new(file, 0, 0),
+			consumed: 0,
+		}
+	}
+	
+	pub fn include(&mut self, lexemes: Vec<Lexeme>, file: usize) {
+		self.frames.push(Frame {lexemes: Rc::new(lexemes), position: 0, origin: Origin::File(file)});
+	}
+	
+	pub fn expand(&mut self, lexemes: Vec<Lexeme>, expansion: usize) {
+		self.frames.push(Frame {lexemes: Rc::new(lexemes), position: 0, origin: Origin::Expansion(expansion)});
+	}
+	
+	// Files that are part way through being read, outermost first.
+	pub fn files(&self) -> impl Iterator<Item = usize> + '_ {
+		self.frames.iter().filter_map(|frame| match frame.origin {
+			Origin::File(file) => Some(file),
+			Origin::Expansion(_) => None,
+		})
+	}
+	
+	pub fn expansion_depth(&self) -> usize {
+		self.frames.iter().filter(|frame| matches!(frame.origin, Origin::Expansion(_))).count()
+	}
+	
+	// Everything read since `earlier`, which has to be a copy of this stream from before it was read.
+	pub fn since(&self, earlier: &TokenStream) -> Vec<Lexeme> {
+		let mut replay = earlier.clone();
+		let mut lexemes = vec!();
+		
+		while replay.consumed < self.consumed && replay.next().is_some() {
+			lexemes.extend(replay.current.clone());
+		}
+		
+		lexemes
+	}
+	
+	pub fn slice(&self) -> &str {
+		match &self.current {
+			Some(lexeme) => &lexeme.text,
+			None => "",
+		}
+	}
+	
+	// Once the stream runs dry, this is an empty span just after the last token.
+	pub fn span(&self) -> Span {
+		match &self.current {
+			Some(lexeme) => lexeme.span.clone(),
+			None => self.end.clone(),
+		}
+	}
+	
+	pub fn lexeme(&self) -> Option<&Lexeme> {
+		self.current.as_ref()
+	}
+	
+	// Whether the last token read began a line. Running out of tokens counts as starting a new one.
+	pub fn at_line_start(&self) -> bool {
+		match &self.current {
+			Some(lexeme) => lexeme.line_start,
+			None => true,
+		}
+	}
+}
+
+impl Iterator for TokenStream {
+	type Item = Token;
+	
+	fn next(&mut self) -> Option<Token> {
+		loop {
+			let frame = self.frames.last_mut()?;
+			
+			if let Some(lexeme) = frame.lexemes.get(frame.position) {
+				frame.position += 1;
+				self.end = Span {start: lexeme.span.end, ..lexeme.span.clone()};
+				self.current = Some(lexeme.clone());
+				self.consumed += 1;
+				return Some(lexeme.token.clone());
+			}
+			
+			if self.frames.len() == 1 {
+				self.current = None;
+				return None;
+			}
+			self.frames.pop();
+		}
+	}
+}
+
 pub fn print_all(stdout: &mut StandardStream, data: &str, hold: bool) {
-	let mut lex = Token::lexer(&data);
+	let mut lex = Token::lexer(data);
 	
 	'lexing: loop {
 		for _ in 0..30 {
@@ -171,9 +360,10 @@ pub fn print_all(stdout: &mut StandardStream, data: &str, hold: bool) {
 					break 'lexing;
 				}
 			};
-				
+			
 			let col = match token {
 				Token::Error		=> Color::Red,
+				Token::InvalidNumber(_)	=> Color::Red,
 				Token::Whitespace	=> Color::Ansi256(8),
 				Token::Comment		=> Color::Green,
 				Token::Keyword(_)	=> Color::Blue,
@@ -182,8 +372,14 @@ pub fn print_all(stdout: &mut StandardStream, data: &str, hold: bool) {
 				Token::Separator	=> Color::Yellow,
 				Token::OpenBracket	=> Color::Ansi256(166),
 				Token::CloseBracket	=> Color::Ansi256(166),
+				Token::CurrentAddress	=> Color::Ansi256(105),
+				Token::SectionStart	=> Color::Ansi256(105),
+				Token::AnonymousLabel	=> Color::White,
+				Token::OpenParen	=> Color::Ansi256(127),
+				Token::CloseParen	=> Color::Ansi256(127),
 				
 				Token::String		=> Color::Yellow,
+				Token::Character	=> Color::Yellow,
 				Token::Number(_)	=> Color::Ansi256(105),
 				Token::Operator		=> Color::Ansi256(127),
 				Token::Identifier	=> Color::White,
@@ -200,8 +396,33 @@ pub fn print_all(stdout: &mut StandardStream, data: &str, hold: bool) {
 pub fn token_display(lex: &Lexer<Token>, token: &Token) -> String {
 	let string = match token {
 		Token::Keyword(_) => "\n",
-		Token::Error => panic!(" <There's an invalid token here!> "),
 		_ => ""
 	};
 	format!("{}{}", string, lex.slice())
+}
+
+pub fn token_name(token: &Token) -> &'static str {
+	match token {
+		Token::Error		=> "Invalid Token",
+		Token::InvalidNumber(_)	=> "Invalid Number",
+		Token::Whitespace	=> "Whitespace",
+		Token::Comment		=> "Comment",
+		Token::Keyword(_)	=> "Keyword",
+		
+		Token::Register(_)	=> "Register",
+		Token::Separator	=> "Separator",
+		Token::OpenBracket	=> "Open Bracket",
+		Token::CloseBracket	=> "Close Bracket",
+		Token::CurrentAddress	=> "Current Address",
+		Token::SectionStart	=> "Section Start",
+		Token::AnonymousLabel	=> "Anonymous Label",
+		Token::OpenParen	=> "Open Parenthesis",
+		Token::CloseParen	=> "Close Parenthesis",
+		
+		Token::String		=> "String",
+		Token::Character	=> "Character",
+		Token::Number(_)	=> "Number",
+		Token::Operator		=> "Operator",
+		Token::Identifier	=> "Identifier",
+	}
 }
\ No newline at end of file
@@ -0,0 +1,16 @@
+#![allow(non_snake_case, clippy::upper_case_acronyms)]
+
+pub mod lexer;
+pub mod keywords;
+pub mod parser;
+pub mod encoder;
+pub mod diagnostic;
+pub mod output;
+pub mod expression;
+pub mod source;
+pub mod macros;
+pub mod symbols;
+pub mod listing;
+mod assembler;
+
+pub use assembler::{Assembler, Listing, Program};
\ No newline at end of file
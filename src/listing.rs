@@ -0,0 +1,223 @@
+use std::collections::{HashMap, HashSet};
+use crate::assembler::{Listing, Program};
+use crate::source::{Span, Sources};
+use crate::symbols;
+
+const WORDS_PER_ROW: usize = 3;
+
+// Prints every line of the source in the order it was assembled, each instruction next to its address and
+// the words it became. Included files and the lines macros and REPTs expand to are indented under the line
+// that brought them in.
+struct Printer<'a> {
+	sources: &'a Sources,
+	text: String,
+	lines: HashMap<usize, Vec<&'a str>>,
+	line_starts: HashMap<usize, Vec<usize>>,
+	// Files that were included at each line, keyed by the file and line of the INCLUDE.
+	includes: HashMap<(usize, usize), Vec<usize>>,
+	next_line: HashMap<usize, usize>,
+	started: HashSet<usize>,
+	open: Vec<usize>,
+}
+
+pub fn render(program: &Program, sources: &Sources) -> String {
+	let mut printer = Printer::new(sources);
+	
+	for entry in program.listing.iter() {
+		printer.entry(entry);
+	}
+	for file in sources.ids() {
+		if sources.get(file).included_from.is_none() {
+			let end = printer.lines(file).len();
+			printer.advance(file, end);
+		}
+	}
+	printer.close_all();
+	
+	printer.text + &symbol_table(program)
+}
+
+impl<'a> Printer<'a> {
+	fn new(sources: &'a Sources) -> Printer<'a> {
+		let mut printer = Printer {
+			sources,
+			text: String::new(),
+			lines: HashMap::new(),
+			line_starts: HashMap::new(),
+			includes: HashMap::new(),
+			next_line: HashMap::new(),
+			started: HashSet::new(),
+			open: vec!(),
+		};
+		
+		for file in sources.ids() {
+			let text = &sources.get(file).text;
+			printer.lines.insert(file, text.lines().collect());
+			printer.line_starts.insert(file, std::iter::once(0).chain(text.match_indices('\n').map(|(index, _)| index + 1)).collect());
+		}
+		for file in sources.ids() {
+			if let Some(from) = &sources.get(file).included_from {
+				let site = printer.site(from).0;
+				let line = printer.line_of(&site);
+				printer.includes.entry((site.file, line)).or_default().push(file);
+			}
+		}
+		
+		printer
+	}
+	
+	fn lines(&self, file: usize) -> &[&'a str] {
+		&self.lines[&file]
+	}
+	
+	fn line_of(&self, span: &Span) -> usize {
+		self.line_starts[&span.file].partition_point(|start| *start <= span.start) - 1
+	}
+	
+	// Where an expanded span was written out in full, and how many expansions deep it was.
+	fn site(&self, span: &Span) -> (Span, usize) {
+		let mut site = span.clone();
+		let mut depth = 0;
+		while let Some(id) = site.expansion {
+			site = self.sources.expansion(id).invocation.clone();
+			depth += 1;
+		}
+		(site, depth)
+	}
+	
+	fn include_depth(&self, file: usize) -> usize {
+		match &self.sources.get(file).included_from {
+			Some(from) => self.include_depth(self.site(from).0.file) + 1,
+			None => 0,
+		}
+	}
+	
+	fn entry(&mut self, entry: &Listing) {
+		let (site, expansions) = self.site(&entry.span);
+		let line = self.line_of(&site);
+		let depth = self.include_depth(site.file) + expansions;
+		
+		if expansions > 0 {
+			// A REPT's body comes after it in the same file, so it's printed before the passes through it.
+			let body_line = self.line_of(&entry.span);
+			let until = if entry.span.file == site.file && body_line > line {body_line + 1} else {line + 1};
+			self.advance(site.file, until);
+			let text = self.lines(entry.span.file).get(body_line).copied().unwrap_or("");
+			self.row(entry, depth, text.trim_start());
+		} else if self.started.contains(&site.file) && self.next_line[&site.file] > line {
+			// Another instruction on a line that's already been printed.
+			self.advance(site.file, line);
+			self.row(entry, depth, "");
+		} else {
+			self.advance(site.file, line);
+			let text = self.lines(site.file).get(line).copied().unwrap_or("");
+			self.row(entry, depth, text);
+			self.next_line.insert(site.file, line + 1);
+		}
+	}
+	
+	// Prints the lines of a file that haven't been printed yet, up to `until`.
+	fn advance(&mut self, file: usize, until: usize) {
+		self.open_file(file);
+		self.print_lines(file, until);
+	}
+	
+	// A file is started by printing its includer up to and including the INCLUDE, and anything started
+	// after it is finished off when it's returned to.
+	fn open_file(&mut self, file: usize) {
+		if self.started.insert(file) {
+			self.next_line.insert(file, 0);
+			match self.sources.get(file).included_from.clone() {
+				Some(from) => {
+					let site = self.site(&from).0;
+					let line = self.line_of(&site);
+					self.advance(site.file, line + 1);
+				},
+				None => self.close_all(),
+			}
+			self.open.push(file);
+		} else if self.open.contains(&file) {
+			while let Some(&top) = self.open.last() {
+				if top == file {
+					break;
+				}
+				self.open.pop();
+				let end = self.lines(top).len();
+				self.print_lines(top, end);
+			}
+		}
+	}
+	
+	fn close_all(&mut self) {
+		while let Some(top) = self.open.pop() {
+			let end = self.lines(top).len();
+			self.print_lines(top, end);
+		}
+	}
+	
+	// Files included on these lines that never came up in the listing only have definitions in them,
+	// so they're printed in full right after their INCLUDE.
+	fn print_lines(&mut self, file: usize, until: usize) {
+		let depth = self.include_depth(file);
+		while self.next_line[&file] < until.min(self.lines(file).len()) {
+			let line = self.next_line[&file];
+			self.next_line.insert(file, line + 1);
+			let text = self.lines(file)[line];
+			self.plain(depth, text);
+			
+			for included in self.includes.get(&(file, line)).cloned().unwrap_or_default() {
+				if self.started.insert(included) {
+					self.next_line.insert(included, 0);
+					self.open.push(included);
+					let end = self.lines(included).len();
+					self.print_lines(included, end);
+					self.open.pop();
+				}
+			}
+		}
+	}
+	
+	fn row(&mut self, entry: &Listing, depth: usize, text: &str) {
+		let mut rows = entry.words.chunks(WORDS_PER_ROW);
+		let first = rows.next().unwrap_or(&[]);
+		self.text += format!("{:04X}  {:<14}  {}{}", entry.address, words(first), indent(depth), text).trim_end();
+		self.text += "\n";
+		
+		for (index, row) in rows.enumerate() {
+			let address = entry.address.wrapping_add(((index + 1) * WORDS_PER_ROW) as u16);
+			self.text += &format!("{:04X}  {}\n", address, words(row));
+		}
+	}
+	
+	fn plain(&mut self, depth: usize, text: &str) {
+		self.text += format!("{:22}{}{}", "", indent(depth), text).trim_end();
+		self.text += "\n";
+	}
+}
+
+fn words(words: &[u16]) -> String {
+	words.iter().map(|word| format!("{:04X}", word)).collect::<Vec<String>>().join(" ")
+}
+
+fn indent(depth: usize) -> String {
+	"    ".repeat(depth)
+}
+
+// Anonymous labels and macro LOCALs are left out, since their names were made up by the assembler.
+fn symbol_table(program: &Program) -> String {
+	let mut names: Vec<&String> = program.symbols.values.keys()
+		.filter(|name| !name.starts_with(':') && !name.contains('#'))
+		.collect();
+	names.sort();
+	
+	let mut text = format!("\nSymbols:\n{:<32}  {:<6}  {}\n", "Name", "Value", "Kind");
+	for name in names {
+		let kind = match &program.symbols.definitions[name] {
+			symbols::Definition {span: None, ..} => "-D",
+			definition => symbols::kind_name(definition.kind),
+		};
+		text += &format!("{:<32}  {:04X}    {}\n", name, program.symbols.values[name], kind);
+	}
+	
+	text
+}
\ No newline at end of file
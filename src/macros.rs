@@ -0,0 +1,83 @@
+use std::collections::HashMap;
+use crate::keywords::Keyword;
+use crate::lexer::{Lexeme, Token};
+use crate::source::Span;
+
+#[derive(Debug)]
+pub struct Macro {
+	pub parameters: Vec<String>,
+	pub locals: Vec<String>,
+	pub body: Vec<Lexeme>,
+	pub span: Span,
+}
+
+pub type Macros = HashMap<String, Macro>;
+
+impl Macro {
+	// Swaps the arguments in for the parameters and gives every LOCAL label a name that's unique to this expansion.
+	// Macros defined inside the body are left alone until they're expanded themselves.
+	pub fn expand(&self, arguments: &[Vec<Lexeme>], expansion: usize) -> Vec<Lexeme> {
+		let mut lexemes = vec!();
+		let mut depth = 0;
+		
+		for lexeme in self.body.iter() {
+			let mut lexeme = Lexeme {span: Span {expansion: Some(expansion), ..lexeme.span.clone()}, ..lexeme.clone()};
+			
+			match lexeme.token {
+				Token::Keyword(Keyword::MACRO) => depth += 1,
+				Token::Keyword(Keyword::ENDM) => depth -= 1,
+				Token::Identifier if depth == 0 => {
+					if let Some(index) = self.parameters.iter().position(|parameter| *parameter == lexeme.text) {
+						lexemes.extend(substitute(&lexeme, &arguments[index]));
+						continue;
+					}
+					// `#` can't appear in source, so these can't clash with anything written by hand.
+					if self.locals.contains(&lexeme.text) {
+						lexeme.text = format!("{}#{}", lexeme.text, expansion);
+					}
+				},
+				_ => (),
+			}
+			
+			lexemes.push(lexeme);
+		}
+		
+		lexemes
+	}
+}
+
+// Arguments that are expressions get wrapped in parentheses, so `p * 2` given `1 + 1` is 4 and not 3.
+fn substitute(parameter: &Lexeme, argument: &[Lexeme]) -> Vec<Lexeme> {
+	let mut lexemes = argument.to_vec();
+	
+	if lexemes.len() > 1 && !matches!(lexemes[0].token, Token::OpenBracket) {
+		let first = lexemes[0].span.clone();
+		let last = lexemes[lexemes.len() - 1].span.clone();
+		lexemes.insert(0, parenthesis(Token::OpenParen, "(", Span {end: first.start, ..first}));
+		lexemes.push(parenthesis(Token::CloseParen, ")", Span {start: last.end, ..last}));
+	}
+	
+	for lexeme in lexemes.iter_mut() {
+		lexeme.line_start = false;
+	}
+	if let Some(first) = lexemes.first_mut() {
+		first.spaced_before = parameter.spaced_before;
+		first.line_start = parameter.line_start;
+	}
+	if let Some(last) = lexemes.last_mut() {
+		last.spaced_after = parameter.spaced_after;
+	}
+	
+	lexemes
+}
+
+fn parenthesis(token: Token, text: &str, span: Span) -> Lexeme {
+	Lexeme {
+		token,
+		text: text.to_owned(),
+		span,
+		spaced_before: false,
+		spaced_after: false,
+		line_start: false,
+	}
+}
\ No newline at end of file
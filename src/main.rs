@@ -1,58 +1,139 @@
+#![allow(non_snake_case, clippy::upper_case_acronyms)]
 
 use std::{
 	fs,
-	path::Path,
-	collections::HashMap
+	io::{self, IsTerminal, Read, Write},
+	path::PathBuf,
 };
 use termcolor::{StandardStream, ColorChoice};
-use logos::Logos;
-use lexer::Token;
+use asm_19_assembler::{lexer, listing, output, Assembler};
+use asm_19_assembler::diagnostic::{Diagnostic, Severity};
+use asm_19_assembler::source::Sources;
+use cli::{Command, Options, Verbosity};
 
-mod lexer;
-mod keywords;
-mod parser;
-mod encoder;
+mod cli;
 
+const EXIT_FAILURE: i32 = 1;
+const EXIT_USAGE: i32 = 2;
 
 fn main() {
-	let args: Vec<String> = std::env::args().collect();
-	let path = Path::new(&args[1]);
-	let hold: bool = if args.len() > 2 {args[2].parse().unwrap()} else {false};
+	let options = match cli::parse_args(std::env::args().skip(1)) {
+		Ok(Command::Assemble(options)) => options,
+		Ok(Command::Help) => {
+			print!("{}", cli::USAGE);
+			return;
+		},
+		Err(message) => {
+			eprintln!("error: {}\n\n{}", message, cli::USAGE);
+			std::process::exit(EXIT_USAGE);
+		},
+	};
 	
-	let data = std::fs::read_to_string(path).unwrap();
+	if !assemble(&options) {
+		std::process::exit(EXIT_FAILURE);
+	}
+}
+
+fn assemble(options: &Options) -> bool {
+	let mut stderr = StandardStream::stderr(color_choice(options.color));
 	
-	let mut stdout = StandardStream::stdout(ColorChoice::Always);
+	let data = if options.reads_stdin() {
+		let mut data = String::new();
+		io::stdin().read_to_string(&mut data).map(|_| data)
+	} else {
+		fs::read_to_string(&options.input)
+	};
+	let data = match data {
+		Ok(data) => data,
+		Err(err) => {
+			eprintln!("error: Could not read {}: {}", options.input_name(), err);
+			return false;
+		},
+	};
 	
-	lexer::print_all(&mut stdout, &data, hold);
+	if options.verbosity == Verbosity::Verbose {
+		lexer::print_all(&mut stderr, &data, options.hold);
+	}
 	
-	let mut lex = Token::lexer(&data);
+	let mut assembler = Assembler::new();
+	for dir in options.include_dirs.iter() {
+		assembler.include_dir(dir.clone());
+	}
+	for (name, value) in options.defines.iter() {
+		assembler.define(name, *value);
+	}
+	let path = if options.reads_stdin() {None} else {Some(options.input.clone())};
+	assembler.add_source(options.input_name(), path, data);
 	
-	let mut partially_encoded_file: Vec<encoder::Byte> = vec!();
-	let mut constants: HashMap<String, u16> = HashMap::new();
+	let program = match assembler.assemble() {
+		Ok(program) => program,
+		Err(diagnostics) => {
+			report(&mut stderr, &diagnostics, assembler.sources());
+			return false;
+		},
+	};
+	for warning in program.warnings.iter() {
+		warning.render(&mut stderr, assembler.sources()).unwrap();
+	}
 	
-	loop {
-		match parser::parse(&mut lex) {
-			Some(instruction) => {
-				for byte in encoder::partially_encode(&instruction, &mut constants, partially_encoded_file.len() as u16).into_iter() {
-					print!("{:?}\t", byte);
-					partially_encoded_file.push(byte);
-				}
-				println!("{:?}", instruction);
-			},
-			None => break
-		};
+	if options.verbosity == Verbosity::Verbose || options.listing.is_some() {
+		let listing = listing::render(&program, assembler.sources());
+		if options.verbosity == Verbosity::Verbose {
+			eprint!("{}", listing);
+		}
+		if let Some(path) = &options.listing {
+			if let Err(err) = fs::write(path, listing) {
+				eprintln!("error: Could not write {}: {}", path.display(), err);
+				return false;
+			}
+		}
 	}
 	
-	let all_bytes = encoder::encode_identifiers(&constants, &partially_encoded_file);
+	let (origin, all_bytes) = program.words();
+	let encoded_file = match output::encode(options.format, &program.segments, options.memory) {
+		Ok(encoded_file) => encoded_file,
+		Err(message) => {
+			eprintln!("error: {}", message);
+			return false;
+		},
+	};
 	
-	let mut encoded_file = vec!();
+	let output_path = match &options.output {
+		None if options.reads_stdin() => PathBuf::from("-"),
+		Some(path) if path.as_os_str() == "-" => path.clone(),
+		requested => output::output_path(&options.input, requested.as_deref(), options.format),
+	};
 	
-	for value in all_bytes.into_iter() {
-		encoded_file.push((value >> 8) as u8);
-		encoded_file.push(value as u8)
+	let written = if output_path.as_os_str() == "-" {
+		io::stdout().write_all(&encoded_file).and_then(|_| io::stdout().flush())
+	} else {
+		fs::write(&output_path, &encoded_file)
 	};
+	if let Err(err) = written {
+		eprintln!("error: Could not write {}: {}", output_path.display(), err);
+		return false;
+	}
 	
-	let output_path = format!("{}\\{}.bin", path.parent().unwrap().to_str().unwrap(), path.file_stem().unwrap().to_str().unwrap());
+	if options.verbosity >= Verbosity::Normal && output_path.as_os_str() != "-" {
+		eprintln!("Assembled {} words at {:#06X} into {}", all_bytes.len(), origin, output_path.display());
+	}
 	
-	fs::write(output_path, &encoded_file).unwrap();
+	true
+}
+
+fn report(stderr: &mut StandardStream, diagnostics: &[Diagnostic], sources: &Sources) {
+	for diagnostic in diagnostics.iter() {
+		diagnostic.render(stderr, sources).unwrap();
+	}
+	
+	let errors = diagnostics.iter().filter(|diagnostic| diagnostic.severity == Severity::Error).count();
+	let plural = if errors == 1 {""} else {"s"};
+	eprintln!("Assembly failed with {} error{}", errors, plural);
+}
+
+fn color_choice(choice: ColorChoice) -> ColorChoice {
+	match choice {
+		ColorChoice::Auto if !io::stderr().is_terminal() => ColorChoice::Never,
+		choice => choice,
+	}
 }
\ No newline at end of file
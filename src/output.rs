@@ -0,0 +1,393 @@
+use std::path::{Path, PathBuf};
+use crate::encoder::Segment;
+
+#[derive(Clone, Copy)]
+#[derive(Debug)]
+#[derive(PartialEq)]
+pub enum Format {
+	Binary,
+	BinaryLittleEndian,
+	IntelHex,
+	IntelHexWords,
+	SRecord,
+	Logisim,
+	ReadMemH,
+	ReadMemB,
+	MIF,
+	COE,
+}
+
+// The size of the memory an image is for and what goes in the words the program doesn't use.
+// Without a depth, images stop at the end of the program.
+#[derive(Clone, Copy)]
+#[derive(Debug)]
+pub struct Memory {
+	pub depth: Option<u32>,
+	pub fill: u16,
+}
+
+impl Format {
+	pub fn from_name(name: &str) -> Option<Format> {
+		match name.to_lowercase().as_str() {
+			"bin" | "binary" => Some(Format::Binary),
+			"bin-le" | "binary-le" => Some(Format::BinaryLittleEndian),
+			"ihex" | "hex" => Some(Format::IntelHex),
+			"ihex16" | "hex16" => Some(Format::IntelHexWords),
+			"srec" | "s19" | "s28" => Some(Format::SRecord),
+			"logisim" => Some(Format::Logisim),
+			"readmemh" | "memh" => Some(Format::ReadMemH),
+			"readmemb" | "memb" => Some(Format::ReadMemB),
+			"mif" => Some(Format::MIF),
+			"coe" => Some(Format::COE),
+			_ => None,
+		}
+	}
+	
+	pub fn extension(&self) -> &'static str {
+		match self {
+			Format::Binary | Format::BinaryLittleEndian => "bin",
+			Format::IntelHex | Format::IntelHexWords => "hex",
+			Format::SRecord => "srec",
+			Format::Logisim => "txt",
+			Format::ReadMemH | Format::ReadMemB => "mem",
+			Format::MIF => "mif",
+			Format::COE => "coe",
+		}
+	}
+}
+
+// Places the output next to the source unless told otherwise. An override naming an existing
+// directory still gets the source's file name.
+pub fn output_path(input: &Path, requested: Option<&Path>, format: Format) -> PathBuf {
+	// Only the last extension is swapped, so `game.v2.a19` becomes `game.v2.bin` rather than `game.bin`.
+	match requested {
+		Some(path) if path.is_dir() => path.join(Path::new(input.file_name().unwrap_or_else(|| "out".as_ref())).with_extension(format.extension())),
+		Some(path) => path.to_path_buf(),
+		None => input.with_extension(format.extension()),
+	}
+}
+
+// Lays the segments out as one contiguous image starting at the lowest origin, zeroing the gaps between them.
+pub fn flatten(segments: &[Segment<u16>]) -> (u16, Vec<u16>) {
+	let origin = segments.iter().map(|segment| segment.origin).min().unwrap_or(0);
+	let mut image = vec!();
+	
+	for segment in segments.iter() {
+		let start = (segment.origin - origin) as usize;
+		if image.len() < start + segment.words.len() {
+			image.resize(start + segment.words.len(), 0);
+		}
+		image[start..start + segment.words.len()].copy_from_slice(&segment.words);
+	}
+	
+	(origin as u16, image)
+}
+
+// Raw images are flattened from the lowest origin, while the record formats give every segment its own
+// addresses and leave the gaps out. Memory images always start from address 0.
+pub fn encode(format: Format, segments: &[Segment<u16>], memory: Memory) -> Result<Vec<u8>, String> {
+	Ok(match format {
+		Format::Binary => flatten(segments).1.iter().flat_map(|word| word.to_be_bytes()).collect(),
+		Format::BinaryLittleEndian => flatten(segments).1.iter().flat_map(|word| word.to_le_bytes()).collect(),
+		Format::IntelHex => intel_hex(segments, 2),
+		Format::IntelHexWords => intel_hex(segments, 1),
+		Format::SRecord => s_records(segments),
+		Format::Logisim => logisim(&image(segments, memory)?),
+		Format::ReadMemH => read_mem(&image(segments, memory)?, |word| format!("{:04X}", word)),
+		Format::ReadMemB => read_mem(&image(segments, memory)?, |word| format!("{:016b}", word)),
+		Format::MIF => mif(&image(segments, memory)?),
+		Format::COE => coe(&image(segments, memory)?),
+	})
+}
+
+fn image(segments: &[Segment<u16>], memory: Memory) -> Result<Vec<u16>, String> {
+	let end = segments.iter().map(|segment| segment.end()).max().unwrap_or(0);
+	let depth = memory.depth.unwrap_or(end);
+	if end > depth {
+		return Err(format!("The program runs up to address {:#06X}, past the end of a {} word memory", end - 1, depth));
+	}
+	
+	let mut image = vec!(memory.fill; depth as usize);
+	for segment in segments.iter() {
+		image[segment.origin as usize..segment.end() as usize].copy_from_slice(&segment.words);
+	}
+	
+	Ok(image)
+}
+
+// Runs of the same word, so long stretches of fill don't take a line each.
+fn runs(words: &[u16]) -> Vec<(usize, usize, u16)> {
+	let mut runs: Vec<(usize, usize, u16)> = vec!();
+	
+	for (address, word) in words.iter().enumerate() {
+		match runs.last_mut() {
+			Some((_, length, value)) if value == word => *length += 1,
+			_ => runs.push((address, 1, *word)),
+		}
+	}
+	
+	runs
+}
+
+// Logisim's "v2.0 raw" image, eight words to a line, where `n*word` repeats a word n times.
+fn logisim(words: &[u16]) -> Vec<u8> {
+	let entries: Vec<String> = runs(words).iter().flat_map(|(_, length, word)| match length {
+		1..=3 => vec!(format!("{:x}", word); *length),
+		_ => vec!(format!("{}*{:x}", length, word)),
+	}).collect();
+	
+	let mut text = "v2.0 raw\n".to_owned();
+	for line in entries.chunks(8) {
+		text += &line.join(" ");
+		text += "\n";
+	}
+	text.into_bytes()
+}
+
+// One word per line, for Verilog's $readmemh and $readmemb.
+fn read_mem(words: &[u16], format: fn(u16) -> String) -> Vec<u8> {
+	words.iter().map(|word| format(*word) + "\n").collect::<String>().into_bytes()
+}
+
+// Altera's Memory Initialization File.
+fn mif(words: &[u16]) -> Vec<u8> {
+	let mut text = format!("WIDTH=16;\nDEPTH={};\n\nADDRESS_RADIX=HEX;\nDATA_RADIX=HEX;\n\nCONTENT BEGIN\n", words.len());
+	for (address, length, word) in runs(words) {
+		match length {
+			1 => text += &format!("\t{:04X} : {:04X};\n", address, word),
+			_ => text += &format!("\t[{:04X}..{:04X}] : {:04X};\n", address, address + length - 1, word),
+		}
+	}
+	text += "END;\n";
+	text.into_bytes()
+}
+
+// Xilinx's coefficient file, as used to initialise block RAM.
+fn coe(words: &[u16]) -> Vec<u8> {
+	let values: Vec<String> = words.iter().map(|word| format!("{:04X}", word)).collect();
+	format!("memory_initialization_radix=16;\nmemory_initialization_vector=\n{};\n", values.join(",\n")).into_bytes()
+}
+
+// Words are split into big-endian bytes like the raw image. `scale` is how many addresses each word takes,
+// 2 when every byte has its own address and 1 when every word does.
+fn intel_hex(segments: &[Segment<u16>], scale: u32) -> Vec<u8> {
+	let mut text = String::new();
+	let mut upper = 0;
+	
+	for segment in segments.iter() {
+		let bytes: Vec<u8> = segment.words.iter().flat_map(|word| word.to_be_bytes()).collect();
+		let mut address = segment.origin * scale;
+		let mut index = 0;
+		
+		while index < bytes.len() {
+			// Records can't cross into the next 64K, which needs an extended linear address record first.
+			if address >> 16 != upper {
+				upper = address >> 16;
+				text += &intel_hex_record(0x04, 0, &(upper as u16).to_be_bytes());
+			}
+			let room = ((0x1_0000 - (address & 0xFFFF)) * 2 / scale) as usize;
+			let length = 16.min(bytes.len() - index).min(room);
+			text += &intel_hex_record(0x00, address as u16, &bytes[index..index + length]);
+			index += length;
+			address += length as u32 * scale / 2;
+		}
+	}
+	
+	text += &intel_hex_record(0x01, 0, &[]);
+	text.into_bytes()
+}
+
+fn intel_hex_record(kind: u8, address: u16, data: &[u8]) -> String {
+	let mut record = vec!(data.len() as u8);
+	record.extend(address.to_be_bytes());
+	record.push(kind);
+	record.extend(data);
+	let checksum = record.iter().fold(0u8, |sum, byte| sum.wrapping_add(*byte)).wrapping_neg();
+	record.push(checksum);
+	
+	format!(":{}\n", hex_string(&record))
+}
+
+// Byte addressed, with 16 bit addresses (S1/S9) if everything fits under 64K and 24 bit ones (S2/S8) if not.
+fn s_records(segments: &[Segment<u16>]) -> Vec<u8> {
+	let end = segments.iter().map(|segment| segment.end() * 2).max().unwrap_or(0);
+	let (data_kind, end_kind, address_length) = if end > 0x1_0000 {(2, 8, 3)} else {(1, 9, 2)};
+	let mut text = s_record(0, 0, 2, &[]);
+	let mut count = 0u32;
+	
+	for segment in segments.iter() {
+		let bytes: Vec<u8> = segment.words.iter().flat_map(|word| word.to_be_bytes()).collect();
+		for (index, chunk) in bytes.chunks(16).enumerate() {
+			text += &s_record(data_kind, segment.origin * 2 + index as u32 * 16, address_length, chunk);
+			count += 1;
+		}
+	}
+	
+	if count <= 0xFFFF {
+		text += &s_record(5, count, 2, &[]);
+	} else {
+		text += &s_record(6, count, 3, &[]);
+	}
+	let start = segments.iter().map(|segment| segment.origin * 2).min().unwrap_or(0);
+	text += &s_record(end_kind, start, address_length, &[]);
+	text.into_bytes()
+}
+
+fn s_record(kind: u8, address: u32, address_length: usize, data: &[u8]) -> String {
+	let mut record = vec!((address_length + data.len() + 1) as u8);
+	record.extend(&address.to_be_bytes()[4 - address_length..]);
+	record.extend(data);
+	let checksum = !record.iter().fold(0u8, |sum, byte| sum.wrapping_add(*byte));
+	record.push(checksum);
+	
+	format!("S{}{}\n", kind, hex_string(&record))
+}
+
+fn hex_string(bytes: &[u8]) -> String {
+	bytes.iter().map(|byte| format!("{:02X}", byte)).collect()
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+	
+	// Crosses into the second 64K of bytes halfway through the middle segment.
+	fn segments() -> Vec<Segment<u16>> {
+		vec!(
+			Segment {origin: 0x0000, words: vec!(0x1234, 0xABCD), span: None},
+			Segment {origin: 0x7FFF, words: (1..=10).collect(), span: None},
+			Segment {origin: 0xFFFE, words: vec!(0xFFFF, 0xEEEE), span: None},
+		)
+	}
+	
+	fn text(format: Format, segments: &[Segment<u16>]) -> String {
+		String::from_utf8(encode(format, segments, Memory {depth: None, fill: 0}).unwrap()).unwrap()
+	}
+	
+	#[test]
+	fn intel_hex_bytes() {
+		assert_eq!(text(Format::IntelHex, &segments()), [
+			":040000001234ABCD3E",
+			":02FFFE00000100",
+			":020000040001F9",
+			":1000000000020003000400050006000700080009C4",
+			":02001000000AE4",
+			":04FFFC00FFFFEEEE27",
+			":00000001FF",
+		].join("\n") + "\n");
+	}
+	
+	#[test]
+	fn intel_hex_words() {
+		assert_eq!(text(Format::IntelHexWords, &segments()), [
+			":040000001234ABCD3E",
+			":107FFF00000100020003000400050006000700084E",
+			":048007000009000A62",
+			":04FFFE00FFFFEEEE25",
+			":00000001FF",
+		].join("\n") + "\n");
+	}
+	
+	#[test]
+	fn s_records_16_bit() {
+		let segments = vec!(Segment {origin: 0x0000, words: vec!(0x1234), span: None});
+		assert_eq!(text(Format::SRecord, &segments), "S0030000FC\nS10500001234B4\nS5030001FB\nS9030000FC\n");
+	}
+	
+	// Anything past 64K bytes needs 24 bit addresses. There can't be more than 8192 data records, so the
+	// count always fits in an S5.
+	#[test]
+	fn s_records_24_bit() {
+		assert_eq!(text(Format::SRecord, &segments()), [
+			"S0030000FC",
+			"S2080000001234ABCD39",
+			"S21400FFFE00010002000300040005000600070008CA",
+			"S20801000E0009000AD5",
+			"S20801FFFCFFFFEEEE21",
+			"S5030004F8",
+			"S804000000FB",
+		].join("\n") + "\n");
+	}
+	
+	// Two segments with gaps before, between and after them once the image is padded out to 16 words.
+	fn gapped() -> Vec<Segment<u16>> {
+		vec!(
+			Segment {origin: 0x0002, words: vec!(0x1234, 0x1234), span: None},
+			Segment {origin: 0x0008, words: vec!(0xABCD), span: None},
+		)
+	}
+	
+	fn padded(format: Format) -> String {
+		String::from_utf8(encode(format, &gapped(), Memory {depth: Some(16), fill: 0xFFFF}).unwrap()).unwrap()
+	}
+	
+	fn padded_words() -> Vec<&'static str> {
+		let mut words = vec!("FFFF"; 16);
+		words[2] = "1234";
+		words[3] = "1234";
+		words[8] = "ABCD";
+		words
+	}
+	
+	#[test]
+	fn logisim_runs() {
+		assert_eq!(padded(Format::Logisim), "v2.0 raw\nffff ffff 1234 1234 4*ffff abcd 7*ffff\n");
+		
+		let segments = vec!(Segment {origin: 0x0000, words: (1..=9).collect(), span: None});
+		assert_eq!(text(Format::Logisim, &segments), "v2.0 raw\n1 2 3 4 5 6 7 8\n9\n");
+	}
+	
+	#[test]
+	fn read_mem_lines() {
+		assert_eq!(padded(Format::ReadMemH), padded_words().join("\n") + "\n");
+		
+		let segments = vec!(Segment {origin: 0x0001, words: vec!(0x8001), span: None});
+		assert_eq!(text(Format::ReadMemB, &segments), "0000000000000000\n1000000000000001\n");
+	}
+	
+	#[test]
+	fn mif_ranges() {
+		assert_eq!(padded(Format::MIF), [
+			"WIDTH=16;",
+			"DEPTH=16;",
+			"",
+			"ADDRESS_RADIX=HEX;",
+			"DATA_RADIX=HEX;",
+			"",
+			"CONTENT BEGIN",
+			"\t[0000..0001] : FFFF;",
+			"\t[0002..0003] : 1234;",
+			"\t[0004..0007] : FFFF;",
+			"\t0008 : ABCD;",
+			"\t[0009..000F] : FFFF;",
+			"END;",
+		].join("\n") + "\n");
+	}
+	
+	#[test]
+	fn coe_vector() {
+		assert_eq!(padded(Format::COE), format!("memory_initialization_radix=16;\nmemory_initialization_vector=\n{};\n", padded_words().join(",\n")));
+	}
+	
+	// Without a depth the image ends with the program, and a depth that's too small is an error.
+	#[test]
+	fn image_depth() {
+		assert_eq!(text(Format::ReadMemH, &gapped()), "0000\n0000\n1234\n1234\n0000\n0000\n0000\n0000\nABCD\n");
+		assert_eq!(
+			encode(Format::MIF, &gapped(), Memory {depth: Some(8), fill: 0}),
+			Err("The program runs up to address 0x0008, past the end of a 8 word memory".to_owned())
+		);
+		assert!(encode(Format::COE, &gapped(), Memory {depth: Some(9), fill: 0}).is_ok());
+	}
+	
+	#[test]
+	fn output_paths() {
+		assert_eq!(output_path(Path::new("games/game.a19"), None, Format::Binary), PathBuf::from("games/game.bin"));
+		assert_eq!(output_path(Path::new("game.v1.a19"), None, Format::IntelHex), PathBuf::from("game.v1.hex"));
+		assert_eq!(output_path(Path::new("game.v2.a19"), None, Format::IntelHex), PathBuf::from("game.v2.hex"));
+		assert_eq!(output_path(Path::new("game.a19"), Some(Path::new("out.bin")), Format::SRecord), PathBuf::from("out.bin"));
+		
+		let dir = std::env::temp_dir();
+		assert_eq!(output_path(Path::new("src/game.v2.a19"), Some(&dir), Format::Binary), dir.join("game.v2.bin"));
+	}
+}
\ No newline at end of file
@@ -1,13 +1,32 @@
-use logos::Lexer;
+use std::{
+	collections::HashMap,
+	path::PathBuf,
+};
 use crate::keywords::{Register, Keyword};
 use crate::lexer::*;
+use crate::diagnostic::Diagnostic;
+use crate::source::{Span, Sources};
+use crate::macros::{Macro, Macros};
+use crate::expression::{self, Expression, Location, UnaryOperator, BinaryOperator};
+
+const MAX_EXPANSION_DEPTH: usize = 64;
 
 #[derive(Debug)]
 pub enum Instruction {
-	CONST(String, u16),
-	MARK(String),
-	DATA(Vec<u16>),
-	DSTR(Vec<u16>),
+	CONST(String, Expression, Span),
+	VAR(String, Expression, Span),
+	MARK(String, Span),
+	DATA(Vec<Expression>),
+	DSTR(Vec<u16>, Option<Expression>),
+	DSTRZ(Vec<u16>, Option<Expression>),
+	DSTRL(Vec<u16>, Option<Expression>),
+	DSTRP(Vec<u16>),
+	ORG(Expression, Span),
+	RESERVE(Expression, Span),
+	FILL(Expression, Expression, Span),
+	ALIGN(Expression, Option<Expression>, Span),
+	INCBIN(PathBuf, Option<Expression>, Option<Expression>, Span),
+	INCBINB(PathBuf, Option<Expression>, Option<Expression>, Span),
 	
 	ADD(Target, Target),
 	SUB(Target, Target),
@@ -52,393 +71,1005 @@ pub enum Instruction {
 	EXTI(Target),
 }
 
+impl Instruction {
+	// Every expression the instruction holds, for passes that have to see all of them.
+	pub fn expressions_mut(&mut self) -> Vec<&mut Expression> {
+		match self {
+			Instruction::CONST(_, value, _) | Instruction::VAR(_, value, _) => vec!(value),
+			Instruction::DATA(values) => values.iter_mut().collect(),
+			Instruction::DSTR(_, attribute)
+			| Instruction::DSTRZ(_, attribute)
+			| Instruction::DSTRL(_, attribute) => attribute.iter_mut().collect(),
+			Instruction::ORG(value, _) | Instruction::RESERVE(value, _) => vec!(value),
+			Instruction::FILL(count, value, _) => vec!(count, value),
+			Instruction::ALIGN(alignment, fill, _) => std::iter::once(alignment).chain(fill.iter_mut()).collect(),
+			Instruction::INCBIN(_, offset, length, _)
+			| Instruction::INCBINB(_, offset, length, _) => offset.iter_mut().chain(length.iter_mut()).collect(),
+			
+			Instruction::ADD(a, b) | Instruction::SUB(a, b) | Instruction::MUL(a, b) | Instruction::SMUL(a, b)
+			| Instruction::DIV(a, b) | Instruction::SDIV(a, b) | Instruction::MOD(a, b) | Instruction::SMOD(a, b)
+			| Instruction::AND(a, b) | Instruction::OR(a, b) | Instruction::XOR(a, b)
+			| Instruction::SHL(a, b) | Instruction::SHR(a, b) | Instruction::SAR(a, b)
+			| Instruction::CMP(a, b) | Instruction::SET(a, b) | Instruction::GET(a, b) | Instruction::SWAP(a, b) => {
+				let mut expressions = a.expressions_mut();
+				expressions.append(&mut b.expressions_mut());
+				expressions
+			},
+			
+			Instruction::NEG(a) | Instruction::NOT(a)
+			| Instruction::JG(a) | Instruction::JNG(a) | Instruction::JL(a) | Instruction::JNL(a)
+			| Instruction::JE(a) | Instruction::JNE(a) | Instruction::JMP(a)
+			| Instruction::PUSH(a) | Instruction::POP(a) | Instruction::CALL(a)
+			| Instruction::VPUSH(a) | Instruction::VPOP(a) | Instruction::EXTI(a) => a.expressions_mut(),
+			
+			Instruction::MARK(_, _) | Instruction::DSTRP(_) | Instruction::RET | Instruction::NOP | Instruction::HALT => vec!(),
+		}
+	}
+}
+
 #[derive(Debug)]
 pub enum Target {
 	Register(Register),
-	Literal(Literal),
+	Literal(Expression),
 	FromMem(FromMem),
 }
 
-#[derive(Clone)]
-#[derive(Debug)]
-pub enum Literal {
-	Number(u16),
-	Identifier(bool, String),
+impl Target {
+	pub fn expressions_mut(&mut self) -> Vec<&mut Expression> {
+		match self {
+			Target::Literal(value) => vec!(value),
+			Target::FromMem(FromMem::RegisterLiteral(_, offset))
+			| Target::FromMem(FromMem::TwoRegisterLiteral(_, _, _, offset)) => vec!(offset),
+			_ => vec!(),
+		}
+	}
 }
 
+// The bool is whether the second register is subtracted. Offsets carry their own sign.
 #[derive(Clone)]
 #[derive(Debug)]
 pub enum FromMem {
 	Register(Register),
-	RegisterLiteral(Register, Literal),
+	RegisterLiteral(Register, Expression),
 	TwoRegister(Register, bool, Register),
-	TwoRegisterLiteral(Register, bool, Register, Literal),
+	TwoRegisterLiteral(Register, bool, Register, Expression),
 }
 
-pub fn parse(lex: &mut Lexer<Token>) -> Option<Instruction> {
-	let token = lex.next();
-	let instruction = match token {
-		Some(token) => match token {
-				Token::Keyword(keyword) => {
-					let instruction = match_keyword(lex, keyword);
-					Some(instruction)
-				},
-				_ => panic!("Unexpected token: {:?}", token)
+// What parsing keeps track of from one instruction to the next.
+pub struct Context {
+	pub sources: Sources,
+	pub macros: Macros,
+	conditionals: Vec<Conditional>,
+	scope: Option<String>,
+	anonymous: usize,
+}
+
+// An IF that's still waiting for its ENDIF. Once a branch has been assembled the rest are skipped.
+struct Conditional {
+	span: Span,
+	taken: bool,
+	seen_else: bool,
+}
+
+impl Context {
+	pub fn new(sources: Sources) -> Context {
+		Context {sources, macros: Macros::new(), conditionals: vec!(), scope: None, anonymous: 0}
+	}
+}
+
+// INCLUDEs, macros and conditional assembly are handled here rather than turned into instructions,
+// the tokens they bring in (or leave out) just carry on from where they were.
+// IF conditions can only use the constants and labels defined before them.
+// Instructions come with the span from their keyword to their last operand.
+pub fn parse(lex: &mut TokenStream, context: &mut Context, constants: &HashMap<String, u16>, location: Location) -> Result<Option<(Instruction, Span)>, Diagnostic> {
+	loop {
+		let token = match lex.next() {
+			Some(token) => token,
+			None => match context.conditionals.pop() {
+				Some(conditional) => return Err(Diagnostic::error(conditional.span, "IF is missing its ENDIF")),
+				None => return Ok(None),
 			},
-		None => None
-	};
+		};
+		let resume = lex.clone();
+		
+		let result = match token {
+			Token::Keyword(Keyword::INCLUDE) => include(lex, &mut context.sources).map(|_| None),
+			// A broken definition still reads up to its ENDM, so its body isn't assembled by mistake.
+			Token::Keyword(Keyword::MACRO) => match define_macro(lex, &mut context.macros) {
+				Ok(()) => continue,
+				Err(diagnostic) => return Err(diagnostic),
+			},
+			Token::Keyword(Keyword::ENDM) => Err(Diagnostic::error(lex.span(), "ENDM without a matching MACRO")),
+			// Like MACRO, a broken REPT still reads up to its ENDR.
+			Token::Keyword(Keyword::REPT) => match repeat(lex, &mut context.sources, constants, location) {
+				Ok(()) => continue,
+				Err(diagnostic) => return Err(diagnostic),
+			},
+			Token::Keyword(Keyword::ENDR) => Err(Diagnostic::error(lex.span(), "ENDR without a matching REPT")),
+			Token::Keyword(Keyword::LOCAL) => Err(Diagnostic::error(lex.span(), "LOCAL can only be used inside a MACRO")),
+			Token::Keyword(keyword @ Keyword::IF)
+			| Token::Keyword(keyword @ Keyword::IFDEF)
+			| Token::Keyword(keyword @ Keyword::IFNDEF) => {
+				let start = lex.span();
+				let condition = condition(lex, &keyword, constants, location, context.scope.as_deref(), context.anonymous);
+				let taken = *condition.as_ref().unwrap_or(&true);
+				context.conditionals.push(Conditional {span: start.to(&lex.span()), taken, seen_else: false});
+				// A condition that can't be worked out skips the whole IF, rather than guessing at a branch.
+				if condition.is_err() || !taken {
+					skip_branch(lex);
+				}
+				match condition {
+					Ok(_) => continue,
+					Err(diagnostic) => return Err(diagnostic),
+				}
+			},
+			Token::Keyword(Keyword::ELIF) => {
+				let span = lex.span();
+				match context.conditionals.last_mut() {
+					None => Err(Diagnostic::error(span, "ELIF without a matching IF")),
+					// Whatever follows a misplaced branch is skipped, since the IF has already had its ELSE.
+					Some(conditional) if conditional.seen_else => {
+						let diagnostic = Diagnostic::error(span, "ELIF after ELSE").with_note(conditional.span.clone(), "In this IF");
+						skip_branch(lex);
+						return Err(diagnostic);
+					},
+					Some(conditional) if conditional.taken => {
+						skip_branch(lex);
+						continue;
+					},
+					Some(conditional) => {
+						let condition = condition(lex, &Keyword::ELIF, constants, location, context.scope.as_deref(), context.anonymous);
+						conditional.taken = *condition.as_ref().unwrap_or(&true);
+						if !conditional.taken {
+							skip_branch(lex);
+						}
+						match condition {
+							Ok(_) => continue,
+							Err(diagnostic) => {
+								skip_branch(lex);
+								return Err(diagnostic);
+							},
+						}
+					},
+				}
+			},
+			Token::Keyword(Keyword::ELSE) => {
+				let span = lex.span();
+				match context.conditionals.last_mut() {
+					None => Err(Diagnostic::error(span, "ELSE without a matching IF")),
+					Some(conditional) if conditional.seen_else => {
+						let diagnostic = Diagnostic::error(span, "IF already has an ELSE").with_note(conditional.span.clone(), "In this IF");
+						skip_branch(lex);
+						return Err(diagnostic);
+					},
+					Some(conditional) => {
+						conditional.seen_else = true;
+						if conditional.taken {
+							skip_branch(lex);
+						}
+						conditional.taken = true;
+						continue;
+					},
+				}
+			},
+			Token::Keyword(Keyword::ENDIF) => match context.conditionals.pop() {
+				Some(_) => continue,
+				None => Err(Diagnostic::error(lex.span(), "ENDIF without a matching IF")),
+			},
+			Token::Keyword(keyword) => {
+				let start = lex.span();
+				match_keyword(lex, keyword, &context.sources, &context.macros)
+					.and_then(|instruction| name_labels(instruction, context))
+					.map(|instruction| Some((instruction, start.to(&lex.span()))))
+			},
+			Token::Identifier if context.macros.contains_key(lex.slice()) => invoke_macro(lex, &mut context.sources, &context.macros).map(|_| None),
+			_ => {
+				let mut diagnostic = unexpected(lex, Some(token.clone()), "Unexpected token: Expected Keyword");
+				if let Token::Identifier = token {
+					diagnostic = diagnostic.with_hint("Labels are defined with MARK, and macros have to be defined with MACRO before they're used");
+				}
+				synchronize(lex, &context.macros);
+				return Err(diagnostic);
+			},
+		};
+		
+		match result {
+			Ok(Some(instruction)) => return Ok(Some(instruction)),
+			Ok(None) => (),
+			Err(diagnostic) => {
+				// The failed instruction may have swallowed the next keyword, so start looking from just after its own.
+				*lex = resume;
+				synchronize(lex, &context.macros);
+				return Err(diagnostic);
+			},
+		}
+	}
+}
+
+// Labels without a leading dot open a new scope for the local labels that follow them, which are stored
+// under their full name, so `.loop` after `MARK VSync` is `VSync.loop`. Anonymous labels are numbered in
+// the order they're marked, and references to them are counted from there.
+fn name_labels(mut instruction: Instruction, context: &mut Context) -> Result<Instruction, Diagnostic> {
+	for expression in instruction.expressions_mut() {
+		expression.resolve_labels(context.scope.as_deref(), context.anonymous)?;
+	}
 	
-	instruction
+	match &mut instruction {
+		Instruction::MARK(name, _) if name == ":" => {
+			*name = expression::anonymous_name(context.anonymous);
+			context.anonymous += 1;
+		},
+		Instruction::MARK(name, span) | Instruction::CONST(name, _, span) | Instruction::VAR(name, _, span) if name.starts_with('.') => {
+			*name = expression::qualify(name, context.scope.as_deref(), span)?;
+		},
+		// Labels a macro marks, LOCAL ones especially, are its own business and leave the caller's scope alone.
+		Instruction::MARK(name, span) if span.expansion.is_none() && !name.contains('#') => context.scope = Some(name.clone()),
+		_ => (),
+	}
+	
+	Ok(instruction)
 }
 
-fn match_keyword(lex: &mut Lexer<Token>, keyword: Keyword) -> Instruction {
+fn condition(lex: &mut TokenStream, keyword: &Keyword, constants: &HashMap<String, u16>, location: Location, scope: Option<&str>, anonymous: usize) -> Result<bool, Diagnostic> {
 	match keyword {
-		Keyword::CONST => {
-			assemble_CONST(lex)
-		}
-		Keyword::MARK => {
-			assemble_MARK(lex)
-		}
-		Keyword::DATA => {
-			assemble_DATA(lex)
+		Keyword::IFDEF | Keyword::IFNDEF => {
+			let defined = match lex.next() {
+				Some(Token::Identifier) => constants.contains_key(&expression::qualify(lex.slice(), scope, &lex.span())?),
+				token => return Err(unexpected(lex, token, &format!("Malformed {:?}: Expected Identifier", keyword))),
+			};
+			Ok(defined == matches!(keyword, Keyword::IFDEF))
+		},
+		_ => {
+			let mut expression = get_single_expression(lex, &format!("Malformed {:?}", keyword))?;
+			expression.resolve_labels(scope, anonymous)?;
+			match expression.evaluate(constants, location) {
+				Ok(value) => Ok(value != 0),
+				Err(diagnostic) => Err(diagnostic.with_hint("Use IFDEF to check whether a name is defined")),
+			}
+		},
+	}
+}
+
+// Skips to the next ELIF, ELSE or ENDIF of the current IF, leaving it to be read next.
+// IFs nested inside the skipped part are skipped along with it.
+fn skip_branch(lex: &mut TokenStream) {
+	let mut depth = 0;
+	loop {
+		let mut peek = lex.clone();
+		match peek.next() {
+			None => return,
+			Some(Token::Keyword(Keyword::IF)) | Some(Token::Keyword(Keyword::IFDEF)) | Some(Token::Keyword(Keyword::IFNDEF)) => depth += 1,
+			Some(Token::Keyword(Keyword::ENDIF)) if depth > 0 => depth -= 1,
+			Some(Token::Keyword(Keyword::ENDIF)) => return,
+			Some(Token::Keyword(Keyword::ELIF)) | Some(Token::Keyword(Keyword::ELSE)) if depth == 0 => return,
+			_ => (),
 		}
-		Keyword::DSTR => {
-			assemble_DSTR(lex)
+		*lex = peek;
+	}
+}
+
+fn synchronize(lex: &mut TokenStream, macros: &Macros) {
+	loop {
+		let mut peek = lex.clone();
+		match peek.next() {
+			Some(Token::Keyword(_)) | None => break,
+			Some(Token::Identifier) if peek.at_line_start() && macros.contains_key(peek.slice()) => break,
+			_ => *lex = peek,
 		}
-		
-		Keyword::HALT => Instruction::HALT,
-		Keyword::NOP => Instruction::NOP,
-		Keyword::RET => Instruction::RET,
-		
-		Keyword::NEG	=> Instruction::NEG		(get_next_operand(lex, false)),
-		Keyword::NOT	=> Instruction::NOT		(get_next_operand(lex, false)),
-		Keyword::PUSH	=> Instruction::PUSH	(get_next_operand(lex, false)),
-		Keyword::POP	=> Instruction::POP		(get_next_operand(lex, false)),
-		Keyword::VPUSH	=> Instruction::VPUSH	(get_next_operand(lex, false)),
-		Keyword::VPOP	=> Instruction::VPOP	(get_next_operand(lex, false)),
-		Keyword::CALL	=> Instruction::CALL	(get_next_operand(lex, false)),
-		Keyword::JMP	=> Instruction::JMP		(get_next_operand(lex, false)),
-		Keyword::JG		=> Instruction::JG		(get_next_operand(lex, false)),
-		Keyword::JNG	=> Instruction::JNG		(get_next_operand(lex, false)),
-		Keyword::JL		=> Instruction::JL		(get_next_operand(lex, false)),
-		Keyword::JNL	=> Instruction::JNL		(get_next_operand(lex, false)),
-		Keyword::JE		=> Instruction::JE		(get_next_operand(lex, false)),
-		Keyword::JNE	=> Instruction::JNE		(get_next_operand(lex, false)),
-		Keyword::EXTI	=> Instruction::EXTI	(get_next_operand(lex, false)),
-		
-		Keyword::ADD	=> Instruction::ADD		(get_next_operand(lex, false), get_next_operand(lex, true)),
-		Keyword::SUB	=> Instruction::SUB		(get_next_operand(lex, false), get_next_operand(lex, true)),
-		Keyword::MUL	=> Instruction::MUL		(get_next_operand(lex, false), get_next_operand(lex, true)),
-		Keyword::DIV	=> Instruction::DIV		(get_next_operand(lex, false), get_next_operand(lex, true)),
-		Keyword::MOD	=> Instruction::MOD		(get_next_operand(lex, false), get_next_operand(lex, true)),
-		Keyword::SMUL	=> Instruction::SMUL	(get_next_operand(lex, false), get_next_operand(lex, true)),
-		Keyword::SDIV	=> Instruction::SDIV	(get_next_operand(lex, false), get_next_operand(lex, true)),
-		Keyword::SMOD	=> Instruction::SMOD	(get_next_operand(lex, false), get_next_operand(lex, true)),
-		Keyword::AND	=> Instruction::AND		(get_next_operand(lex, false), get_next_operand(lex, true)),
-		Keyword::OR		=> Instruction::OR		(get_next_operand(lex, false), get_next_operand(lex, true)),
-		Keyword::XOR	=> Instruction::XOR		(get_next_operand(lex, false), get_next_operand(lex, true)),
-		Keyword::SHL	=> Instruction::SHL		(get_next_operand(lex, false), get_next_operand(lex, true)),
-		Keyword::SHR	=> Instruction::SHR		(get_next_operand(lex, false), get_next_operand(lex, true)),
-		Keyword::SAR	=> Instruction::SAR		(get_next_operand(lex, false), get_next_operand(lex, true)),
-		Keyword::SET	=> Instruction::SET		(get_next_operand(lex, false), get_next_operand(lex, true)),
-		Keyword::GET	=> Instruction::GET		(get_next_operand(lex, false), get_next_operand(lex, true)),
-		Keyword::SWAP	=> Instruction::SWAP	(get_next_operand(lex, false), get_next_operand(lex, true)),
-		Keyword::CMP	=> Instruction::CMP		(get_next_operand(lex, false), get_next_operand(lex, true)),
-		
-		_ => panic!("Not implemented"),
 	}
 }
 
-fn assemble_CONST(lex: &mut Lexer<Token>) -> Instruction {
-	let mut token = lex.next();
+fn include(lex: &mut TokenStream, sources: &mut Sources) -> Result<(), Diagnostic> {
+	let start = lex.span();
+	let name = match lex.next() {
+		Some(Token::String) => lex.slice()[1..lex.slice().len() - 1].to_owned(),
+		token => return Err(unexpected(lex, token, "Malformed INCLUDE: Expected String")),
+	};
+	let span = start.to(&lex.span());
 	
-	let identifier = match token {
-		Some(token) => {
-			match token {
-				Token::Identifier => {
-						lex.slice()
-					},
-				_ => panic!("Malformed CONST: Expected Identifier, got {:?}, {}", token, token_display(lex, &token))
+	let path = match sources.resolve(&name, span.file) {
+		Some(path) => path,
+		None => {
+			let mut searched = vec!(sources.directory(span.file));
+			for directory in sources.include_dirs().iter() {
+				if !searched.contains(directory) {
+					searched.push(directory.clone());
+				}
 			}
+			let searched: Vec<String> = searched.iter()
+				.map(|directory| if directory.as_os_str().is_empty() {"\".\"".to_owned()} else {format!("\"{}\"", directory.display())})
+				.collect();
+			return Err(Diagnostic::error(span, format!("INCLUDE could not find \"{}\"", name))
+				.with_hint(format!("Searched {}, add more directories with -I", searched.join(", "))));
+		},
+	};
+	
+	// Every file is only ever included once, so shared definitions don't need guards.
+	if let Some(file) = sources.find(&path) {
+		if lex.files().any(|active| active == file) {
+			let chain: Vec<String> = lex.files().skip_while(|active| *active != file)
+				.map(|active| sources.get(active).name.clone())
+				.collect();
+			return Err(Diagnostic::error(span, format!("Circular INCLUDE of \"{}\"", path.display()))
+				.with_hint(format!("Include chain: {} -> {}", chain.join(" -> "), sources.get(file).name)));
 		}
-		None => panic!("Malformed CONST: Expected Identifier, enountered EOF")
+		return Ok(());
+	}
+	
+	let file = match sources.load(&path, span.clone()) {
+		Ok(file) => file,
+		Err(err) => return Err(Diagnostic::error(span, format!("INCLUDE could not read \"{}\": {}", path.display(), err))),
 	};
+	lex.include(sources.tokens(file), file);
 	
-	token = lex.next();
+	Ok(())
+}
+
+fn define_macro(lex: &mut TokenStream, macros: &mut Macros) -> Result<(), Diagnostic> {
+	let start = lex.span();
+	let header = macro_header(lex);
+	let body = block_body(lex, &start, Keyword::MACRO, Keyword::ENDM)?;
+	let (name, name_span, parameters) = header?;
 	
-	let value = match token {
-		Some(token) => {
-			match token {
-				Token::Number(num) => num,
-				Token::Operator => {
-					match assemble_signed_number(lex) {
-						Ok(value) => value,
-						Err(token) => panic!("Malformed CONST: Expected number, got {:?}, {}", token, token_display(lex, &token))
+	if let Some(existing) = macros.get(&name) {
+		return Err(Diagnostic::error(name_span, format!("Macro \"{}\" is already defined", name))
+			.with_note(existing.span.clone(), "First defined here"));
+	}
+	
+	// LOCAL lines only count for this macro, ones inside a nested definition belong to that.
+	let mut locals: Vec<String> = vec!();
+	let mut lexemes = vec!();
+	let mut depth = 0;
+	let mut body = body.into_iter().peekable();
+	while let Some(lexeme) = body.next() {
+		match lexeme.token {
+			Token::Keyword(Keyword::MACRO) => depth += 1,
+			Token::Keyword(Keyword::ENDM) => depth -= 1,
+			Token::Keyword(Keyword::LOCAL) if depth == 0 => {
+				let mut found = false;
+				while let Some(local) = body.next_if(|local| !local.line_start) {
+					match local.token {
+						Token::Identifier => locals.push(local.text),
+						_ => return Err(Diagnostic::error(local.span, format!("Malformed LOCAL: Expected Identifier, got {} \"{}\"", token_name(&local.token), local.text))),
 					}
+					found = true;
 				}
-				_ => panic!("Malformed CONST: Expected Number, got {:?}, {}", token, token_display(lex, &token))
-			}
+				if !found {
+					return Err(Diagnostic::error(lexeme.span, "Malformed LOCAL: Expected Identifier"));
+				}
+				continue;
+			},
+			_ => (),
 		}
-		None => panic!("Malformed CONST: Expected Number, enountered EOF")
-	};
+		lexemes.push(lexeme);
+	}
 	
-	Instruction::CONST(identifier.to_owned(), value)
+	macros.insert(name, Macro {parameters, locals, body: lexemes, span: start.to(&name_span)});
+	Ok(())
 }
 
-fn assemble_MARK(lex: &mut Lexer<Token>) -> Instruction {
-	let token = lex.next();
+// The name and parameters have to be on the same line as MACRO, the body starts on the next one.
+fn macro_header(lex: &mut TokenStream) -> Result<(String, Span, Vec<String>), Diagnostic> {
+	let name = match lex.next() {
+		Some(Token::Identifier) if !lex.at_line_start() => lex.slice().to_owned(),
+		token => return Err(unexpected(lex, token, "Malformed MACRO: Expected Identifier")),
+	};
+	let name_span = lex.span();
 	
-	let identifier = match token {
-		Some(token) => {
-			match token {
-				Token::Identifier => {
-						lex.slice()
-					},
-				_ => panic!("Malformed MARK: Expected Identifier, got {:?}, {}", token, token_display(lex, &token))
-			}
+	let mut parameters: Vec<String> = vec!();
+	loop {
+		let mut peek = lex.clone();
+		peek.next();
+		if peek.at_line_start() {
+			break;
 		}
-		None => panic!("Malformed MARK: Expected Identifier, enountered EOF")
-	};
+		match lex.next() {
+			Some(Token::Identifier) if parameters.iter().any(|parameter| parameter == lex.slice()) => {
+				return Err(Diagnostic::error(lex.span(), format!("Macro parameter \"{}\" is listed twice", lex.slice())));
+			},
+			Some(Token::Identifier) => parameters.push(lex.slice().to_owned()),
+			token => return Err(unexpected(lex, token, "Malformed MACRO: Expected parameter Identifier")),
+		}
+	}
 	
-	Instruction::MARK(identifier.to_owned())
+	Ok((name, name_span, parameters))
 }
 
-fn assemble_DATA(lex: &mut Lexer<Token>) -> Instruction {
-	let mut values: Vec<u16> = vec!();
-	
-	let mut old_lex = lex.clone();
+// Reads everything up to the matching `close`, including any blocks of the same kind nested inside.
+fn block_body(lex: &mut TokenStream, start: &Span, open: Keyword, close: Keyword) -> Result<Vec<Lexeme>, Diagnostic> {
+	let mut body = vec!();
+	let mut depth = 0;
 	
-	let mut value_count: usize = 0;
 	loop {
-		let token = lex.next();
+		match lex.next() {
+			Some(Token::Keyword(keyword)) if keyword == close && depth == 0 => return Ok(body),
+			Some(Token::Keyword(keyword)) if keyword == close => depth -= 1,
+			Some(Token::Keyword(keyword)) if keyword == open => depth += 1,
+			Some(_) => (),
+			None => return Err(Diagnostic::error(start.clone(), format!("{:?} is missing its {:?}", open, close))),
+		}
+		body.extend(lex.lexeme().cloned());
+	}
+}
+
+// The body is spliced in once per pass, with the counter (if there is one) replaced by the pass number
+// starting from 0. The count has to be known by the time the REPT is reached.
+fn repeat(lex: &mut TokenStream, sources: &mut Sources, constants: &HashMap<String, u16>, location: Location) -> Result<(), Diagnostic> {
+	let start = lex.span();
+	let header = repeat_header(lex, constants, location);
+	let span = start.to(&lex.span());
+	let body = block_body(lex, &start, Keyword::REPT, Keyword::ENDR)?;
+	let (count, counter) = header?;
+	
+	if lex.expansion_depth() >= MAX_EXPANSION_DEPTH {
+		return Err(Diagnostic::error(span, format!("REPT nested more than {} deep inside macros and other REPTs", MAX_EXPANSION_DEPTH)));
+	}
+	
+	let mut lexemes = vec!();
+	let mut first = None;
+	for pass in 0..count {
+		let expansion = sources.add_expansion(format!("pass {} of this REPT", pass), span.clone());
+		first = first.or(Some(expansion));
 		
-		let value = match token {
-			Some(token) => {
-				match token {
-					Token::Number(num) => num,
-					Token::Operator => {
-						match assemble_signed_number(lex) {
-							Ok(value) => value,
-							Err(token) => panic!("Malformed DATA: Expected number, got {:?}, {}", token, token_display(lex, &token))
-						}
-					}
-					_ => break
-				}
+		for lexeme in body.iter() {
+			let mut lexeme = Lexeme {span: Span {expansion: Some(expansion), ..lexeme.span.clone()}, ..lexeme.clone()};
+			if matches!(lexeme.token, Token::Identifier) && counter.as_ref() == Some(&lexeme.text) {
+				lexeme.token = Token::Number(pass);
+				lexeme.text = pass.to_string();
 			}
-			None => break,
-		};
-		
-		values.push(value);
-		
-		value_count += 1;
+			lexemes.push(lexeme);
+		}
 	}
 	
-	for _ in 0..value_count {
-		old_lex.next();
+	if let Some(expansion) = first {
+		lex.expand(lexemes, expansion);
 	}
 	
-	*lex = old_lex;
+	Ok(())
+}
+
+// The counter's name has to be on the same line as REPT, like a macro's parameters.
+fn repeat_header(lex: &mut TokenStream, constants: &HashMap<String, u16>, location: Location) -> Result<(u16, Option<String>), Diagnostic> {
+	let start = lex.span();
+	let count = get_single_expression(lex, "Malformed REPT")?;
+	let span = start.to(&lex.span());
+	let count = match count.evaluate(constants, location)? {
+		count @ 0..=0xFFFF => count as u16,
+		count => return Err(Diagnostic::error(span, format!("REPT count must be between 0 and 65535, got {}", count))),
+	};
 	
-	Instruction::DATA(values)
+	let mut peek = lex.clone();
+	peek.next();
+	if peek.at_line_start() {
+		return Ok((count, None));
+	}
+	
+	match lex.next() {
+		Some(Token::Identifier) => Ok((count, Some(lex.slice().to_owned()))),
+		token => Err(unexpected(lex, token, "Malformed REPT: Expected counter Identifier or a new line")),
+	}
 }
 
-fn assemble_DSTR(lex: &mut Lexer<Token>) -> Instruction {
-	let mut token = lex.next();
+fn invoke_macro(lex: &mut TokenStream, sources: &mut Sources, macros: &Macros) -> Result<(), Diagnostic> {
+	let name = lex.slice().to_owned();
+	let invocation = lex.span();
+	let definition = &macros[&name];
+	let context = format!("Malformed invocation of {}", name);
 	
-	let mut values: Vec<u16> = match token {
-		Some(token) => {
-			match token {
-				Token::String => decompile_string(lex.slice()),
-				_ => panic!("Malformed DSTR: Expected String, got {:?}, {}", token, token_display(lex, &token))
-			}
+	// Arguments are the operands on the rest of the line.
+	let mut arguments = vec!();
+	loop {
+		let mut peek = lex.clone();
+		peek.next();
+		if peek.at_line_start() {
+			break;
 		}
-		None => panic!("Malformed DSTR: Expected String, encountered EOF")
-	};
+		let before = lex.clone();
+		skip_operand(lex, &context)?;
+		arguments.push(lex.since(&before));
+	}
 	
-	token = lex.next();
-	let append: Option<u16> = match token {
-		Some(token) => {
-			match token {
-				Token::Operator => {
-					match assemble_signed_number(lex) {
-						Ok(value) => Some(value),
-						Err(token) => panic!("Malformed DSTR: Expected Number after signage, got {:?}, {}", token, token_display(lex, &token))
-					}
-				},
-				Token::Number(value) => Some(value),
-				_ => None,
+	if arguments.len() != definition.parameters.len() {
+		let plural = if definition.parameters.len() == 1 {""} else {"s"};
+		return Err(Diagnostic::error(invocation.to(&lex.span()), format!("Macro \"{}\" takes {} argument{}, got {}", name, definition.parameters.len(), plural, arguments.len()))
+			.with_hint(format!("Parameters: {}", if definition.parameters.is_empty() {"none".to_owned()} else {definition.parameters.join(", ")})));
+	}
+	
+	if lex.expansion_depth() >= MAX_EXPANSION_DEPTH {
+		return Err(Diagnostic::error(invocation, format!("Macro expansion nested more than {} deep", MAX_EXPANSION_DEPTH))
+			.with_hint(format!("Check whether \"{}\" ends up invoking itself", name)));
+	}
+	
+	let expansion = sources.add_expansion(format!("this expansion of macro \"{}\"", name), invocation);
+	lex.expand(definition.expand(&arguments, expansion), expansion);
+	
+	Ok(())
+}
+
+// Moves past one operand without building it, for collecting macro arguments.
+fn skip_operand(lex: &mut TokenStream, context: &str) -> Result<(), Diagnostic> {
+	let mut peek = lex.clone();
+	match peek.next() {
+		Some(Token::Register(_)) | Some(Token::String) => *lex = peek,
+		Some(Token::OpenBracket) => {
+			*lex = peek;
+			loop {
+				match lex.next() {
+					Some(Token::CloseBracket) => break,
+					token @ Some(Token::Keyword(_)) | token @ None => return Err(unexpected(lex, token, &format!("{}: Expected Close Bracket", context))),
+					_ => (),
+				}
 			}
-		}
-		None => None,
-	};
+		},
+		_ => {
+			get_expression(lex, context)?;
+		},
+	}
 	
-	match append {
-		Some(appendix) => {
-			for value in values.iter_mut() {
-				*value = (*value & 0x00FF) | (appendix << 8);
+	Ok(())
+}
+
+fn match_keyword(lex: &mut TokenStream, keyword: Keyword, sources: &Sources, macros: &Macros) -> Result<Instruction, Diagnostic> {
+	let instruction = match keyword {
+		Keyword::CONST => {
+			let (identifier, value, span) = assemble_CONST(lex, "CONST")?;
+			Instruction::CONST(identifier, value, span)
+		}
+		Keyword::VAR => {
+			let (identifier, value, span) = assemble_CONST(lex, "VAR")?;
+			Instruction::VAR(identifier, value, span)
+		}
+		Keyword::MARK => {
+			assemble_MARK(lex)?
+		}
+		Keyword::DATA => {
+			assemble_DATA(lex, macros)?
+		}
+		Keyword::DSTR => {
+			let (values, attribute) = assemble_DSTR(lex, "DSTR", macros)?;
+			Instruction::DSTR(values, attribute)
+		}
+		Keyword::DSTRZ => {
+			let (values, attribute) = assemble_DSTR(lex, "DSTRZ", macros)?;
+			Instruction::DSTRZ(values, attribute)
+		}
+		Keyword::DSTRL => {
+			let (values, attribute) = assemble_DSTR(lex, "DSTRL", macros)?;
+			Instruction::DSTRL(values, attribute)
+		}
+		Keyword::DSTRP => {
+			let mut peek = lex.clone();
+			peek.next();
+			let start = peek.span();
+			match assemble_DSTR(lex, "DSTRP", macros)? {
+				(values, None) => Instruction::DSTRP(values),
+				(_, Some(_)) => return Err(Diagnostic::error(start.to(&lex.span()), "Malformed DSTRP: Packed strings can't take an attribute")
+					.with_hint("DSTRP fills both bytes of every word with characters")),
 			}
 		}
-		None => ()
+		
+		Keyword::ORG => {
+			let start = lex.span();
+			let address = get_single_expression(lex, "Malformed ORG")?;
+			Instruction::ORG(address, start.to(&lex.span()))
+		}
+		Keyword::RESERVE => {
+			let start = lex.span();
+			let count = get_single_expression(lex, "Malformed RESERVE")?;
+			Instruction::RESERVE(count, start.to(&lex.span()))
+		}
+		Keyword::FILL => {
+			let start = lex.span();
+			let count = get_expression(lex, "Malformed FILL")?;
+			let value = get_expression(lex, "Malformed FILL")?;
+			Instruction::FILL(count, value, start.to(&lex.span()))
+		}
+		Keyword::ALIGN => {
+			let start = lex.span();
+			let alignment = get_expression(lex, "Malformed ALIGN")?;
+			let fill = if continues_operands(lex, macros) {
+				Some(get_expression(lex, "Malformed ALIGN")?)
+			} else {None};
+			Instruction::ALIGN(alignment, fill, start.to(&lex.span()))
+		}
+		Keyword::INCBIN => {
+			let start = lex.span();
+			let (path, offset, length) = assemble_INCBIN(lex, "INCBIN", sources, macros)?;
+			Instruction::INCBIN(path, offset, length, start.to(&lex.span()))
+		}
+		Keyword::INCBINB => {
+			let start = lex.span();
+			let (path, offset, length) = assemble_INCBIN(lex, "INCBINB", sources, macros)?;
+			Instruction::INCBINB(path, offset, length, start.to(&lex.span()))
+		}
+		
+		// Handled by parse before it gets here.
+		Keyword::INCLUDE | Keyword::MACRO | Keyword::ENDM | Keyword::LOCAL
+		| Keyword::IF | Keyword::IFDEF | Keyword::IFNDEF | Keyword::ELIF | Keyword::ELSE | Keyword::ENDIF
+		| Keyword::REPT | Keyword::ENDR => unreachable!(),
+		
+		Keyword::HALT => Instruction::HALT,
+		Keyword::NOP => Instruction::NOP,
+		Keyword::RET => Instruction::RET,
+		
+		Keyword::NEG	=> Instruction::NEG		(get_next_operand(lex, false)?),
+		Keyword::NOT	=> Instruction::NOT		(get_next_operand(lex, false)?),
+		Keyword::PUSH	=> Instruction::PUSH	(get_next_operand(lex, false)?),
+		Keyword::POP	=> Instruction::POP		(get_next_operand(lex, false)?),
+		Keyword::VPUSH	=> Instruction::VPUSH	(get_next_operand(lex, false)?),
+		Keyword::VPOP	=> Instruction::VPOP	(get_next_operand(lex, false)?),
+		Keyword::CALL	=> Instruction::CALL	(get_next_operand(lex, false)?),
+		Keyword::JMP	=> Instruction::JMP		(get_next_operand(lex, false)?),
+		Keyword::JG		=> Instruction::JG		(get_next_operand(lex, false)?),
+		Keyword::JNG	=> Instruction::JNG		(get_next_operand(lex, false)?),
+		Keyword::JL		=> Instruction::JL		(get_next_operand(lex, false)?),
+		Keyword::JNL	=> Instruction::JNL		(get_next_operand(lex, false)?),
+		Keyword::JE		=> Instruction::JE		(get_next_operand(lex, false)?),
+		Keyword::JNE	=> Instruction::JNE		(get_next_operand(lex, false)?),
+		Keyword::EXTI	=> Instruction::EXTI	(get_next_operand(lex, false)?),
+		
+		Keyword::ADD	=> Instruction::ADD		(get_next_operand(lex, false)?, get_next_operand(lex, true)?),
+		Keyword::SUB	=> Instruction::SUB		(get_next_operand(lex, false)?, get_next_operand(lex, true)?),
+		Keyword::MUL	=> Instruction::MUL		(get_next_operand(lex, false)?, get_next_operand(lex, true)?),
+		Keyword::DIV	=> Instruction::DIV		(get_next_operand(lex, false)?, get_next_operand(lex, true)?),
+		Keyword::MOD	=> Instruction::MOD		(get_next_operand(lex, false)?, get_next_operand(lex, true)?),
+		Keyword::SMUL	=> Instruction::SMUL	(get_next_operand(lex, false)?, get_next_operand(lex, true)?),
+		Keyword::SDIV	=> Instruction::SDIV	(get_next_operand(lex, false)?, get_next_operand(lex, true)?),
+		Keyword::SMOD	=> Instruction::SMOD	(get_next_operand(lex, false)?, get_next_operand(lex, true)?),
+		Keyword::AND	=> Instruction::AND		(get_next_operand(lex, false)?, get_next_operand(lex, true)?),
+		Keyword::OR		=> Instruction::OR		(get_next_operand(lex, false)?, get_next_operand(lex, true)?),
+		Keyword::XOR	=> Instruction::XOR		(get_next_operand(lex, false)?, get_next_operand(lex, true)?),
+		Keyword::SHL	=> Instruction::SHL		(get_next_operand(lex, false)?, get_next_operand(lex, true)?),
+		Keyword::SHR	=> Instruction::SHR		(get_next_operand(lex, false)?, get_next_operand(lex, true)?),
+		Keyword::SAR	=> Instruction::SAR		(get_next_operand(lex, false)?, get_next_operand(lex, true)?),
+		Keyword::SET	=> Instruction::SET		(get_next_operand(lex, false)?, get_next_operand(lex, true)?),
+		Keyword::GET	=> Instruction::GET		(get_next_operand(lex, false)?, get_next_operand(lex, true)?),
+		Keyword::SWAP	=> Instruction::SWAP	(get_next_operand(lex, false)?, get_next_operand(lex, true)?),
+		Keyword::CMP	=> Instruction::CMP		(get_next_operand(lex, false)?, get_next_operand(lex, true)?),
 	};
 	
-	Instruction::DSTR(values)
+	Ok(instruction)
 }
 
-fn get_next_operand(lex: &mut Lexer<Token>, secondary: bool) -> Target {
-	let token = lex.next();
-	
+fn unexpected(lex: &TokenStream, token: Option<Token>, message: &str) -> Diagnostic {
 	match token {
-		Some(token) => {
-			match token {
-				Token::Number(value) => Target::Literal(Literal::Number(value)),
-				Token::Operator => {
-					match assemble_signed_number(lex) {
-						Ok(value) => Target::Literal(Literal::Number(value)),
-						Err(token) => panic!("Malformed Operand: Expected Number after signage, got {:?}, {}", token, token_display(lex, &token)),
-					}
-				},
-				Token::Identifier => Target::Literal(Literal::Identifier(false, lex.slice().to_owned())),
-				Token::Register(reg) => Target::Register(reg),
-				Token::OpenBracket => if secondary {
-					panic!("Syntax Error: Second operand can never be FromMem")
-				} else {Target::FromMem(assemble_from_mem(lex))},
-				_ => panic!("Malformed Operand: Expected Number, Identifier, Register, or FromMem, got {:?}, {}", token, token_display(lex, &token))
-			}
-		}
-		None => panic!("Malformed Operand: Expected Identifier, enountered EOF")
+		Some(Token::Error) => Diagnostic::error(lex.span(), format!("{}, got invalid token \"{}\"", message, lex.slice())),
+		Some(Token::InvalidNumber(reason)) => Diagnostic::error(lex.span(), reason)
+			.with_hint("Words go up to 65535 (0xFFFF), and down to -32768 with a minus sign in front"),
+		Some(token) => Diagnostic::error(lex.span(), format!("{}, got {} \"{}\"", message, token_name(&token), lex.slice())),
+		None => Diagnostic::error(lex.span(), format!("{}, encountered EOF", message)),
 	}
 }
 
-fn decompile_string(string: &str) -> Vec<u16> {
-	// Cut off quotes
-	let string = &string[1..string.len() - 1];
+fn assemble_CONST(lex: &mut TokenStream, keyword: &str) -> Result<(String, Expression, Span), Diagnostic> {
+	let identifier = match lex.next() {
+		Some(Token::Identifier) => lex.slice().to_owned(),
+		Some(Token::Register(_)) => return Err(register_name(lex, keyword)),
+		token => return Err(unexpected(lex, token, &format!("Malformed {}: Expected Identifier", keyword))),
+	};
+	let span = lex.span();
 	
-	let mut values: Vec<u16> = vec!();
+	let value = get_single_expression(lex, &format!("Malformed {}", keyword))?;
+	
+	Ok((identifier, value, span))
+}
+
+fn assemble_MARK(lex: &mut TokenStream) -> Result<Instruction, Diagnostic> {
+	let identifier = match lex.next() {
+		Some(Token::Identifier) => lex.slice(),
+		Some(Token::AnonymousLabel) if lex.slice() == ":" => lex.slice(),
+		Some(Token::Register(_)) => return Err(register_name(lex, "MARK")),
+		token => return Err(unexpected(lex, token, "Malformed MARK: Expected Identifier or :")),
+	};
 	
-	for byte in string.chars() {
-		values.push(byte as u16);
+	Ok(Instruction::MARK(identifier.to_owned(), lex.span()))
+}
+
+fn register_name(lex: &TokenStream, keyword: &str) -> Diagnostic {
+	unexpected(lex, lex.lexeme().map(|lexeme| lexeme.token.clone()), &format!("Malformed {}: Expected Identifier", keyword))
+		.with_hint(format!("\"{}\" is a register, so a symbol with that name could never be used", lex.slice()))
+}
+
+fn assemble_DATA(lex: &mut TokenStream, macros: &Macros) -> Result<Instruction, Diagnostic> {
+	let mut values: Vec<Expression> = vec!();
+	
+	while continues_operands(lex, macros) {
+		values.push(get_expression(lex, "Malformed DATA")?);
 	}
 	
-	values
+	Ok(Instruction::DATA(values))
 }
 
-fn assemble_signed_number(lex: &mut Lexer<Token>) -> Result<u16, Token> {
-	let operator = lex.slice();
-	let negative = match operator {
-		"+" => false,
-		"-" => true,
-		_ => panic!("Invalid number signage operator: {}", operator),
+fn assemble_DSTR(lex: &mut TokenStream, keyword: &str, macros: &Macros) -> Result<(Vec<u16>, Option<Expression>), Diagnostic> {
+	let values: Vec<u16> = match lex.next() {
+		Some(Token::String) => decompile_string(lex.slice(), &lex.span())?,
+		token => return Err(unexpected(lex, token, &format!("Malformed {}: Expected String", keyword))),
 	};
 	
-	let number = match lex.next() {
-		Some(token) => {
-			match token {
-				Token::Number(value) => value,
-				_ => return Err(token),
-			}
+	let append = if continues_operands(lex, macros) {
+		Some(get_expression(lex, &format!("Malformed {}", keyword))?)
+	} else {None};
+	
+	Ok((values, append))
+}
+
+fn assemble_INCBIN(lex: &mut TokenStream, keyword: &str, sources: &Sources, macros: &Macros) -> Result<(PathBuf, Option<Expression>, Option<Expression>), Diagnostic> {
+	// Paths are taken as written, so Windows separators don't need escaping. Missing files are reported by the encoder.
+	let path = match lex.next() {
+		Some(Token::String) => {
+			let name = &lex.slice()[1..lex.slice().len() - 1];
+			let file = lex.span().file;
+			sources.resolve(name, file).unwrap_or_else(|| sources.directory(file).join(name))
 		},
-		None => panic!("Malformed number: Expected Number, encountered EOF")
+		token => return Err(unexpected(lex, token, &format!("Malformed {}: Expected String", keyword))),
 	};
 	
-	Ok(signed_number(negative, number))
+	let offset = if continues_operands(lex, macros) {
+		Some(get_expression(lex, &format!("Malformed {}", keyword))?)
+	} else {None};
+	
+	let length = if offset.is_some() && continues_operands(lex, macros) {
+		Some(get_expression(lex, &format!("Malformed {}", keyword))?)
+	} else {None};
+	
+	Ok((path, offset, length))
 }
 
-fn signed_number(negative: bool, number: u16) -> u16 {
-	if negative {-(number as i16) as u16} else {number}
+fn get_next_operand(lex: &mut TokenStream, secondary: bool) -> Result<Target, Diagnostic> {
+	if starts_expression(lex) {
+		return Ok(Target::Literal(get_expression(lex, "Malformed Operand")?));
+	}
+	
+	let token = lex.next();
+	
+	let target = match token {
+		Some(Token::Register(reg)) => Target::Register(reg),
+		Some(Token::OpenBracket) => if secondary {
+			return Err(Diagnostic::error(lex.span(), "Syntax Error: Second operand can never be FromMem")
+				.with_hint("Load the value into a register with GET first"))
+		} else {Target::FromMem(assemble_from_mem(lex)?)},
+		_ => return Err(unexpected(lex, token, "Malformed Operand: Expected Number, Identifier, Register, or FromMem")),
+	};
+	
+	Ok(target)
 }
 
-fn assemble_from_mem(lex: &mut Lexer<Token>) -> FromMem {
-	let first_register = match lex.next() {
-		Some(token) => {
-			match token {
-				Token::Register(reg) => reg,
-				_ => panic!("Malformed FromMem Operand: Expected Register, found {:?}, {}", token, token_display(lex, &token))
-			}
-		}
-		None => panic!("Malformed FromMem Operand: Expected Register, encountered EOF")
-	};
-	println!("Found first register");
-	
-	let first_offset_subtract = match lex.next() {
-		Some(token) => {
-			match token {
-				Token::Operator => {
-					let operator = lex.slice();
-					match operator {
-						"+" => false,
-						"-" => true,
-						_ => panic!("FromMem Operand: Invalid signage operator: {:?}", operator),
+fn decompile_string(string: &str, span: &Span) -> Result<Vec<u16>, Diagnostic> {
+	// Cut off quotes
+	let string = &string[1..string.len() - 1];
+	let offset = span.start + 1;
+	let file = span.file;
+	
+	let mut values: Vec<u16> = vec!();
+	let mut chars = string.char_indices().peekable();
+	
+	while let Some((start, character)) = chars.next() {
+		let character = if character == '\\' {
+			let escape = match chars.next() {
+				Some((_, escape)) => escape,
+				None => return Err(Diagnostic::error(Span::new(file, offset + start, offset + start + 1), "Unfinished escape sequence")),
+			};
+			
+			match escape {
+				'n' => '\n',
+				'r' => '\r',
+				't' => '\t',
+				'0' => '\0',
+				'\\' | '"' | '\'' => escape,
+				'x' => {
+					let mut digits = String::new();
+					while digits.len() < 2 {
+						match chars.peek() {
+							Some((_, digit)) if digit.is_ascii_hexdigit() => digits.push(*digit),
+							_ => break,
+						}
+						chars.next();
+					}
+					let end = offset + start + 2 + digits.len();
+					match u8::from_str_radix(&digits, 16) {
+						Ok(value) if digits.len() == 2 => value as char,
+						_ => return Err(Diagnostic::error(Span::new(file, offset + start, end), "Malformed escape sequence: \\x must be followed by two hexadecimal digits")),
 					}
 				},
-				Token::CloseBracket => {return FromMem::Register(first_register)},
-				_ => panic!("Malformed FromMem Operand: Expected Operator or Close Bracket, found {:?}, {}", token, token_display(lex, &token))
-			}
-		}
-		None => panic!("Malformed FromMem Operand: Expected Operator or Close Bracket, encountered EOF")
-	};
-	println!("Found sign");
-	
-	let second_register = match lex.next() {
-		Some(token) => {
-			match token {
-				Token::Register(reg) => reg,
-				Token::Number(number) => {
-					expect_close_bracket(lex);
-					return FromMem::RegisterLiteral(first_register, Literal::Number(signed_number(first_offset_subtract, number)))
-				},
-				Token::Identifier => {
-					let from_mem = FromMem::RegisterLiteral(first_register, Literal::Identifier(first_offset_subtract, lex.slice().to_owned()));
-					expect_close_bracket(lex);
-					return from_mem;
+				_ => {
+					let end = offset + start + 1 + escape.len_utf8();
+					return Err(Diagnostic::error(Span::new(file, offset + start, end), format!("Unknown escape sequence \"\\{}\"", escape))
+						.with_hint("Supported escapes are \\n, \\r, \\t, \\0, \\\\, \\\", \\' and \\xHH"))
 				},
-				_ => panic!("Malformed FromMem Operand: Expected Register, Number, or Identifier, found {:?}, {}", token, token_display(lex, &token))
 			}
-		},
-		None => panic!("Malformed FromMem Operand: Expected Register or Offset, ecountered EOF"),
-	};
-	println!("Found second register");
-	
-	let second_offset_subtract = match lex.next() {
-		Some(token) => {
-			match token {
-				Token::Operator => {
-					let operator = lex.slice();
-					match operator {
-						"+" => false,
-						"-" => true,
-						_ => panic!("FromMem Operand: Invalid signage operator: {:?}", operator),
-					}
-				},
-				Token::CloseBracket => {return FromMem::TwoRegister(first_register, first_offset_subtract, second_register);},
-				_ => panic!("Malformed FromMem Operand: Expected Operator or Close Bracket, found {:?}, {}", token, token_display(lex, &token))
+		} else {character};
+		
+		match encode_character(character) {
+			Some(value) => values.push(value),
+			None => {
+				let span = Span::new(file, offset + start, offset + start + character.len_utf8());
+				return Err(Diagnostic::error(span, format!("Character '{}' (U+{:04X}) doesn't fit the A19 character encoding", character, character as u32))
+					.with_hint("Characters have to fit in a byte, use \\xHH for codes that aren't plain text"));
 			}
 		}
-		None => panic!("Malformed FromMem Operand: Expected Operator or Close Bracket, encountered EOF")
+	}
+	
+	Ok(values)
+}
+
+// Characters only get the low byte of a word, the high byte is for attributes.
+fn encode_character(character: char) -> Option<u16> {
+	if (character as u32) <= 0xFF {Some(character as u16)} else {None}
+}
+
+fn decompile_character(lex: &TokenStream) -> Result<u16, Diagnostic> {
+	let values = decompile_string(lex.slice(), &lex.span())?;
+	
+	match values.as_slice() {
+		[value] => Ok(*value),
+		_ => Err(Diagnostic::error(lex.span(), "Character literals must contain exactly one character")
+			.with_hint("Use DSTR for strings")),
+	}
+}
+
+fn starts_expression(lex: &TokenStream) -> bool {
+	let mut peek = lex.clone();
+	match peek.next() {
+		Some(Token::Number(_))
+		| Some(Token::Character)
+		| Some(Token::Identifier)
+		| Some(Token::CurrentAddress)
+		| Some(Token::AnonymousLabel)
+		| Some(Token::SectionStart)
+		| Some(Token::OpenParen) => true,
+		Some(Token::Operator) => UnaryOperator::from_symbol(peek.slice()).is_some() || peek.slice() == "+",
+		_ => false,
+	}
+}
+
+// Lists of values and optional operands run until something that can't be part of them, which includes
+// a macro invoked at the start of the next line.
+fn continues_operands(lex: &TokenStream, macros: &Macros) -> bool {
+	let mut peek = lex.clone();
+	match peek.next() {
+		Some(Token::Identifier) if peek.at_line_start() && macros.contains_key(peek.slice()) => false,
+		_ => starts_expression(lex),
+	}
+}
+
+// One value in a list of operands, which a spaced sign can end. See `peek_binary_operator`.
+fn get_expression(lex: &mut TokenStream, context: &str) -> Result<Expression, Diagnostic> {
+	get_binary_expression(lex, context, 0, true)
+}
+
+// Where only one value can go, like ORG's address, so `ORG 0x10 +2` is 0x12.
+fn get_single_expression(lex: &mut TokenStream, context: &str) -> Result<Expression, Diagnostic> {
+	get_binary_expression(lex, context, 0, false)
+}
+
+fn get_binary_expression(lex: &mut TokenStream, context: &str, min_precedence: u8, split: bool) -> Result<Expression, Diagnostic> {
+	let mut lhs = get_unary_expression(lex, context)?;
+	
+	while let Some((operator, peek)) = peek_binary_operator(lex, split) {
+		if operator.precedence() < min_precedence {
+			break;
+		}
+		*lex = peek;
+		let span = lex.span();
+		let rhs = get_binary_expression(lex, context, operator.precedence() + 1, split)?;
+		lhs = Expression::Binary(operator, Box::new(lhs), Box::new(rhs), span);
+	}
+	
+	Ok(lhs)
+}
+
+// Operands are only separated by whitespace, so `DATA 1 -2` has to be two values while `DATA 1 - 2` and
+// `DATA 1-2` are one. When `split` is set, a sign with space before it but not after it starts a new value
+// instead of continuing this one.
+fn peek_binary_operator(lex: &TokenStream, split: bool) -> Option<(BinaryOperator, TokenStream)> {
+	let mut peek = lex.clone();
+	match peek.next() {
+		Some(Token::Operator) => (),
+		_ => return None,
 	};
-	println!("Found sign");
-	
-	let last_offset = match lex.next() {
-		Some(token) => {
-			match token {
-				Token::Number(number) => Literal::Number(signed_number(first_offset_subtract, number)),
-				Token::Identifier => Literal::Identifier(first_offset_subtract, lex.slice().to_owned()),
-				_ => panic!("Malformed FromMem Operand: Expected Number or Identifier, found {:?}, {}", token, token_display(lex, &token))
+	
+	let operator = BinaryOperator::from_symbol(peek.slice())?;
+	
+	if split && matches!(operator, BinaryOperator::Add | BinaryOperator::Subtract) {
+		match peek.lexeme() {
+			Some(lexeme) if lexeme.spaced_before && !lexeme.spaced_after => return None,
+			_ => (),
+		}
+	}
+	
+	Some((operator, peek))
+}
+
+fn get_unary_expression(lex: &mut TokenStream, context: &str) -> Result<Expression, Diagnostic> {
+	let token = lex.next();
+	
+	let expression = match token {
+		Some(Token::Number(value)) => Expression::Number(value, lex.span()),
+		Some(Token::Character) => Expression::Number(decompile_character(lex)?, lex.span()),
+		Some(Token::Identifier) => Expression::Identifier(lex.slice().to_owned(), lex.span()),
+		Some(Token::CurrentAddress) => Expression::CurrentAddress(lex.span()),
+		Some(Token::AnonymousLabel) if lex.slice() == ":" => {
+			return Err(Diagnostic::error(lex.span(), format!("{}: Anonymous labels are referred to with :- or :+", context))
+				.with_hint("Add more - or + to skip past the nearest ones, like :-- for the one before last"));
+		},
+		Some(Token::AnonymousLabel) => {
+			let steps = lex.slice().len() as i32 - 1;
+			Expression::Anonymous(if lex.slice().starts_with(":-") {-steps} else {steps}, lex.span())
+		},
+		Some(Token::SectionStart) => Expression::SectionStart(lex.span()),
+		Some(Token::Operator) if lex.slice() == "+" => get_unary_expression(lex, context)?,
+		Some(Token::Operator) if UnaryOperator::from_symbol(lex.slice()).is_some() => {
+			let operator = UnaryOperator::from_symbol(lex.slice()).unwrap();
+			let span = lex.span();
+			Expression::Unary(operator, Box::new(get_unary_expression(lex, context)?), span)
+		},
+		// Nothing inside parentheses can be the start of another operand.
+		Some(Token::OpenParen) => {
+			let expression = get_binary_expression(lex, context, 0, false)?;
+			match lex.next() {
+				Some(Token::CloseParen) => expression,
+				token => return Err(unexpected(lex, token, &format!("{}: Expected Close Parenthesis", context))),
 			}
 		},
-		None => panic!("Malformed FromMem Operand: Expected Register or Offset, ecountered EOF"),
+		_ => return Err(unexpected(lex, token, &format!("{}: Expected Number, Identifier, or Expression", context))),
 	};
-	println!("Found last offset");
 	
-	expect_close_bracket(lex);
-	FromMem::TwoRegisterLiteral(first_register, second_offset_subtract, second_register, last_offset)
+	Ok(expression)
 }
 
-fn expect_close_bracket(lex: &mut Lexer<Token>) {
-	match lex.next() {
-		Some(token) => match token {
-			Token::CloseBracket => (),
-			_ => panic!("Malformed FromMem Operand: Expected Close Bracket, found {:?}, {}", token, token_display(lex, &token))
+// `[base ± register ± offset]`, where the register and the offset are both optional but the offset has to come last.
+// Each term keeps its own sign: the register's is the register operation bit, and the offset's is parsed as part of
+// the offset, so `[A-B+4]` adds 4 and `[A-4+2]` is 2 below A.
+fn assemble_from_mem(lex: &mut TokenStream) -> Result<FromMem, Diagnostic> {
+	let base = match lex.next() {
+		Some(Token::Register(reg)) => reg,
+		token => return Err(unexpected(lex, token, "Malformed FromMem Operand: Expected Register")),
+	};
+	
+	let mut register: Option<(bool, Register)> = None;
+	loop {
+		let mut peek = lex.clone();
+		let subtract = match peek.next() {
+			Some(Token::Operator) if peek.slice() == "+" || peek.slice() == "-" => peek.slice() == "-",
+			Some(Token::CloseBracket) => {
+				*lex = peek;
+				return Ok(match register {
+					Some((subtract, second)) => FromMem::TwoRegister(base, subtract, second),
+					None => FromMem::Register(base),
+				});
+			},
+			token => {
+				*lex = peek;
+				return Err(unexpected(lex, token, "Malformed FromMem Operand: Expected + or - or Close Bracket"));
+			},
+		};
+		
+		match peek.next() {
+			Some(Token::Register(second)) if register.is_none() => {
+				*lex = peek;
+				register = Some((subtract, second));
+			},
+			Some(Token::Register(_)) => {
+				*lex = peek;
+				return Err(Diagnostic::error(lex.span(), "Malformed FromMem Operand: Only one register can be added to or subtracted from the first")
+					.with_hint("Work out the sum in a register first"));
+			},
+			_ => break,
 		}
-		None => panic!("Malformed FromMem Operand: Expected Close Bracket, encountered EOF")
+	}
+	
+	let offset = get_single_expression(lex, "Malformed FromMem Operand")?;
+	expect_close_bracket(lex)?;
+	
+	Ok(match register {
+		Some((subtract, second)) => FromMem::TwoRegisterLiteral(base, subtract, second, offset),
+		None => FromMem::RegisterLiteral(base, offset),
+	})
+}
+
+fn expect_close_bracket(lex: &mut TokenStream) -> Result<(), Diagnostic> {
+	match lex.next() {
+		Some(Token::CloseBracket) => Ok(()),
+		token => Err(unexpected(lex, token, "Malformed FromMem Operand: Expected Close Bracket")),
 	}
 }
\ No newline at end of file
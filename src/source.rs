@@ -0,0 +1,130 @@
+use std::{
+	fs,
+	io,
+	ops::Range,
+	path::{Path, PathBuf},
+};
+use crate::lexer::{self, Lexeme};
+
+// A byte range within one of the files in `Sources`, and the macro expansion it was produced by if any.
+#[derive(Clone)]
+#[derive(Debug)]
+#[derive(Default)]
+#[derive(PartialEq)]
+pub struct Span {
+	pub file: usize,
+	pub start: usize,
+	pub end: usize,
+	pub expansion: Option<usize>,
+}
+
+impl Span {
+	pub fn new(file: usize, start: usize, end: usize) -> Span {
+		Span {file, start, end, expansion: None}
+	}
+	
+	// Stretches this span to cover everything up to the end of `other`.
+	pub fn to(&self, other: &Span) -> Span {
+		if other.file == self.file && other.expansion == self.expansion && other.end >= self.start {
+			Span {end: other.end, ..self.clone()}
+		} else {
+			self.clone()
+		}
+	}
+}
+
+#[derive(Debug)]
+pub struct SourceFile {
+	pub name: String,
+	pub path: Option<PathBuf>,
+	pub text: String,
+	// The INCLUDE that brought the file in, for files that weren't given to the assembler directly.
+	pub included_from: Option<Span>,
+}
+
+// One use of a macro or one pass through a REPT. `invocation` covers where it was used, and `description`
+// finishes the sentence "in ...".
+#[derive(Debug)]
+pub struct Expansion {
+	pub description: String,
+	pub invocation: Span,
+}
+
+// Every file that has been read for the program, so spans can be traced back to their text.
+#[derive(Debug)]
+pub struct Sources {
+	files: Vec<SourceFile>,
+	expansions: Vec<Expansion>,
+	include_dirs: Vec<PathBuf>,
+}
+
+impl Sources {
+	pub fn new(include_dirs: Vec<PathBuf>) -> Sources {
+		Sources {files: vec!(), expansions: vec!(), include_dirs}
+	}
+	
+	pub fn add_include_dir(&mut self, dir: PathBuf) {
+		self.include_dirs.push(dir);
+	}
+	
+	pub fn add(&mut self, name: String, path: Option<PathBuf>, text: String) -> usize {
+		self.files.push(SourceFile {name, path, text, included_from: None});
+		self.files.len() - 1
+	}
+	
+	pub fn load(&mut self, path: &Path, included_from: Span) -> io::Result<usize> {
+		let text = fs::read_to_string(path)?;
+		let file = self.add(path.display().to_string(), Some(path.to_path_buf()), text);
+		self.files[file].included_from = Some(included_from);
+		Ok(file)
+	}
+	
+	pub fn get(&self, file: usize) -> &SourceFile {
+		&self.files[file]
+	}
+	
+	pub fn ids(&self) -> Range<usize> {
+		0..self.files.len()
+	}
+	
+	pub fn add_expansion(&mut self, description: String, invocation: Span) -> usize {
+		self.expansions.push(Expansion {description, invocation});
+		self.expansions.len() - 1
+	}
+	
+	pub fn expansion(&self, expansion: usize) -> &Expansion {
+		&self.expansions[expansion]
+	}
+	
+	pub fn tokens(&self, file: usize) -> Vec<Lexeme> {
+		lexer::lex_file(&self.files[file].text, file)
+	}
+	
+	// Relative paths in a file are relative to that file, and stdin's are relative to the working directory.
+	pub fn directory(&self, file: usize) -> PathBuf {
+		match self.files[file].path.as_ref().and_then(|path| path.parent()) {
+			Some(parent) => parent.to_path_buf(),
+			None => PathBuf::new(),
+		}
+	}
+	
+	pub fn find(&self, path: &Path) -> Option<usize> {
+		let path = fs::canonicalize(path).ok()?;
+		self.files.iter().position(|file| match &file.path {
+			Some(file_path) => fs::canonicalize(file_path).ok().as_ref() == Some(&path),
+			None => false,
+		})
+	}
+	
+	// Looks next to the file doing the including first, then through the include directories in order.
+	pub fn resolve(&self, name: &str, from: usize) -> Option<PathBuf> {
+		let mut candidates = vec!(self.directory(from).join(name));
+		candidates.extend(self.include_dirs.iter().map(|directory| directory.join(name)));
+		
+		candidates.into_iter().find(|candidate| candidate.is_file())
+	}
+	
+	pub fn include_dirs(&self) -> &[PathBuf] {
+		&self.include_dirs
+	}
+}
\ No newline at end of file
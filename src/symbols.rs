@@ -0,0 +1,86 @@
+use std::collections::HashMap;
+use crate::diagnostic::Diagnostic;
+use crate::source::Span;
+
+#[derive(Clone, Copy)]
+#[derive(Debug)]
+#[derive(PartialEq)]
+pub enum SymbolKind {
+	Constant,
+	Label,
+	Variable,
+}
+
+// Where a symbol was defined. Symbols passed in with -D don't have a place in the source.
+#[derive(Clone)]
+#[derive(Debug)]
+pub struct Definition {
+	pub kind: SymbolKind,
+	pub span: Option<Span>,
+}
+
+#[derive(Debug)]
+#[derive(Default)]
+pub struct Symbols {
+	pub values: HashMap<String, u16>,
+	pub definitions: HashMap<String, Definition>,
+}
+
+impl Symbols {
+	pub fn new() -> Symbols {
+		Symbols::default()
+	}
+	
+	pub fn define_external(&mut self, name: &str, value: u16) {
+		self.values.insert(name.to_owned(), value);
+		self.definitions.insert(name.to_owned(), Definition {kind: SymbolKind::Constant, span: None});
+	}
+	
+	// Only VARs can be given a new value, and only by another VAR. Anything else defined twice is an error,
+	// since every reference after the second definition would quietly change meaning.
+	// A successful definition can still come with a warning.
+	pub fn define(&mut self, name: &str, value: u16, kind: SymbolKind, span: &Span) -> Result<Option<Diagnostic>, Diagnostic> {
+		if let Some(existing) = self.definitions.get(name) {
+			if !(existing.kind == SymbolKind::Variable && kind == SymbolKind::Variable) {
+				let mut diagnostic = Diagnostic::error(span.clone(), format!("\"{}\" is already defined", name));
+				diagnostic = match &existing.span {
+					Some(first) => diagnostic.with_note(first.clone(), format!("First defined here as a {}", kind_name(existing.kind))),
+					None => diagnostic.with_hint("It was defined on the command line with -D"),
+				};
+				if existing.kind == SymbolKind::Variable || kind == SymbolKind::Variable {
+					diagnostic = diagnostic.with_hint("Only symbols defined with VAR can be given a new value, and only with VAR");
+				}
+				return Err(diagnostic);
+			}
+		}
+		
+		self.values.insert(name.to_owned(), value);
+		self.definitions.entry(name.to_owned()).or_insert(Definition {kind, span: Some(span.clone())});
+		
+		Ok(register_collision(name, span))
+	}
+	
+	pub fn is_variable(&self, name: &str) -> bool {
+		matches!(self.definitions.get(name), Some(Definition {kind: SymbolKind::Variable, ..}))
+	}
+}
+
+pub fn kind_name(kind: SymbolKind) -> &'static str {
+	match kind {
+		SymbolKind::Constant => "CONST",
+		SymbolKind::Label => "MARK",
+		SymbolKind::Variable => "VAR",
+	}
+}
+
+// Names that are written exactly like a register can't be defined at all, but `.sp` or `Stack.SP` can,
+// and are easy to misread as the register.
+fn register_collision(name: &str, span: &Span) -> Option<Diagnostic> {
+	let last = name.rsplit('.').next().unwrap_or(name);
+	
+	match last.to_uppercase().as_str() {
+		"A" | "B" | "C" | "T" | "SP" | "VP" | "PP" | "FL" => Some(Diagnostic::warning(span.clone(), format!("\"{}\" looks like the register {}", name, last.to_uppercase()))
+			.with_hint("Registers aren't case sensitive, so consider a name that can't be mistaken for one")),
+		_ => None,
+	}
+}
\ No newline at end of file